@@ -0,0 +1,185 @@
+use crate::homebrew::{Brew, CaskBrew, Tap, TappedBrew};
+use crate::Result;
+
+#[derive(Fail, Debug)]
+#[fail(display = "Could not read line {} of the Brewfile: {}", line, message)]
+pub struct BrewfileError {
+    line: usize,
+    message: String,
+}
+
+/// The taps and brews read from a Brewfile, along with everything in it dotter had to leave out.
+#[derive(Debug)]
+pub struct Imported {
+    pub taps: Vec<Tap>,
+    pub brews: Vec<Brew>,
+    pub warnings: Vec<String>,
+}
+
+/// Reads the `tap`, `brew` and `cask` lines of a Brewfile as written for `brew bundle`.
+pub fn parse(content: &str) -> Result<Imported> {
+    let mut brews = Vec::new();
+    let mut taps = Vec::new();
+    let mut warnings = Vec::new();
+
+    for (index, line) in content.lines().enumerate() {
+        let number = index + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (directive, rest) = match line.find(char::is_whitespace) {
+            Some(end) => (&line[..end], line[end..].trim_start()),
+            None => (line, ""),
+        };
+
+        if !["tap", "brew", "cask"].contains(&directive) {
+            warnings.push(format!("Skipping line {}, dotter does not support '{}' entries", number, directive));
+            continue;
+        }
+
+        let (name, rest) = quoted(rest).ok_or_else(|| BrewfileError {
+            line: number,
+            message: format!("expected a quoted name after '{}'", directive),
+        })?;
+
+        let options = rest.trim_start().strip_prefix(',').map(str::trim_start);
+        if directive == "tap" {
+            let url = options.and_then(quoted).map(|(url, _)| url.to_string());
+            taps.push(Tap::new(name.to_string(), url));
+            continue;
+        }
+        if options.is_some() {
+            warnings.push(format!("Ignoring the options of '{}' on line {}", name, number));
+        }
+
+        match directive {
+            "cask" => brews.push(Brew::FromCask(CaskBrew::new(name))),
+            _ => match name.rfind('/') {
+                Some(slash) if name[..slash].contains('/') => {
+                    brews.push(Brew::FromTap(TappedBrew::new(&name[..slash], &name[slash + 1..])))
+                }
+                _ => brews.push(Brew::Simple(name.to_string())),
+            },
+        }
+    }
+
+    Ok(Imported { taps, brews, warnings })
+}
+
+/// Splits a leading `"quoted"` or `'quoted'` string off `text`.
+fn quoted(text: &str) -> Option<(&str, &str)> {
+    let quote = text.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let end = text[1..].find(quote)? + 1;
+    Some((&text[1..end], &text[end + 1..]))
+}
+
+/// Writes a Brewfile: taps first, including those brews are installed from, then formulae and
+/// finally casks, each listed once.
+pub fn render<'a, T, B>(taps: T, brews: B) -> String
+where
+    T: IntoIterator<Item = &'a Tap>,
+    B: IntoIterator<Item = &'a Brew>,
+{
+    let mut tapped: Vec<&str> = Vec::new();
+    let mut lines: Vec<String> = Vec::new();
+    let mut formulae: Vec<String> = Vec::new();
+    let mut casks: Vec<String> = Vec::new();
+
+    for tap in taps {
+        if !tapped.contains(&tap.name()) {
+            tapped.push(tap.name());
+            match tap.url() {
+                Some(url) => lines.push(format!("tap \"{}\", \"{}\"", tap.name(), url)),
+                None => lines.push(format!("tap \"{}\"", tap.name())),
+            }
+        }
+    }
+
+    for brew in brews {
+        if let Some(tap) = brew.tap() {
+            if !tapped.contains(&tap) {
+                tapped.push(tap);
+                lines.push(format!("tap \"{}\"", tap));
+            }
+        }
+        if brew.is_cask() {
+            add(&mut casks, format!("cask \"{}\"", brew.name()));
+        } else {
+            add(&mut formulae, format!("brew \"{}\"", brew.name()));
+        }
+    }
+
+    lines.append(&mut formulae);
+    lines.append(&mut casks);
+    lines.iter().map(|line| format!("{}\n", line)).collect()
+}
+
+fn add(lines: &mut Vec<String>, line: String) {
+    if !lines.contains(&line) {
+        lines.push(line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BREWFILE: &str = r#"
+# Development
+tap "homebrew/cask-fonts"
+tap "neovim/neovim"
+tap "acme/private", "https://git.acme.test/homebrew-private.git"
+brew "git"
+brew "neovim/neovim/nvim"
+brew "postgresql@14", restart_service: true
+cask 'iterm2' # the terminal
+mas "Xcode", id: 497799835
+"#;
+
+    #[test]
+    fn it_reads_brews_casks_and_taps() {
+        let imported = parse(BREWFILE).unwrap();
+
+        let taps = serde_json::to_string(&imported.taps).unwrap();
+        assert_eq!(taps, r#"["homebrew/cask-fonts","neovim/neovim",{"name":"acme/private","url":"https://git.acme.test/homebrew-private.git"}]"#);
+        let brews = serde_json::to_string(&imported.brews).unwrap();
+        assert_eq!(brews, r#"["git",{"tap":"neovim/neovim","name":"nvim"},"postgresql@14",{"cask":"iterm2"}]"#);
+        assert_eq!(imported.warnings, vec![
+            "Ignoring the options of 'postgresql@14' on line 8",
+            "Skipping line 10, dotter does not support 'mas' entries",
+        ]);
+    }
+
+    #[test]
+    fn names_have_to_be_quoted() {
+        let error = parse("brew \"git\"\nbrew git\n").unwrap_err();
+
+        assert_eq!(error.to_string(), "Could not read line 2 of the Brewfile: expected a quoted name after 'brew'");
+    }
+
+    #[test]
+    fn it_writes_taps_then_brews_then_casks() {
+        let taps: Vec<Tap> = serde_json::from_str(r#"[{"name": "acme/private", "url": "https://acme.test/tap.git"}]"#).unwrap();
+        let brews: Vec<Brew> = serde_json::from_str(r#"[
+            {"cask": "iterm2"},
+            "git",
+            {"tap": "neovim/neovim", "name": "nvim"},
+            "git"
+        ]"#).unwrap();
+
+        assert_eq!(render(&taps, &brews), "tap \"acme/private\", \"https://acme.test/tap.git\"\ntap \"neovim/neovim\"\nbrew \"git\"\nbrew \"neovim/neovim/nvim\"\ncask \"iterm2\"\n");
+    }
+
+    #[test]
+    fn what_is_written_can_be_read_back() {
+        let imported = parse(BREWFILE).unwrap();
+
+        let again = parse(&render(&imported.taps, &imported.brews)).unwrap();
+
+        assert_eq!(serde_json::to_string(&again.taps).unwrap(), serde_json::to_string(&imported.taps).unwrap());
+        assert_eq!(serde_json::to_string(&again.brews).unwrap(), serde_json::to_string(&imported.brews).unwrap());
+        assert!(again.warnings.is_empty());
+    }
+}
@@ -1,41 +1,118 @@
-use crate::homebrew::Brew;
+use crate::homebrew::{Brews, Taps};
+use crate::packages::Packages;
+use crate::script::Script;
 use crate::shell::ShellCommand;
 use crate::symlinks::Symlink;
+use crate::toolchains;
 use crate::{Command, Context, Explanation, Result};
 
 #[derive(Deserialize, Debug)]
 pub struct Group {
+    /// Names of other groups that have to be applied before this one.
     #[serde(default)]
-    brew: Vec<Brew>,
+    requires: Vec<String>,
+
+    /// Added before any brews are installed.
+    #[serde(default)]
+    taps: Taps,
+
+    #[serde(default)]
+    brew: Brews,
+
+    /// Installed with the system package manager, or the provider each of them names.
+    #[serde(default)]
+    packages: Packages,
+
+    /// Installed with `cargo install`, `npm install -g`, `pipx install` and `go install`.
+    #[serde(default, deserialize_with = "toolchains::cargo")]
+    cargo: Packages,
+
+    #[serde(default, deserialize_with = "toolchains::npm")]
+    npm: Packages,
+
+    #[serde(default, deserialize_with = "toolchains::pipx")]
+    pipx: Packages,
+
+    #[serde(default, deserialize_with = "toolchains::go")]
+    go: Packages,
 
     #[serde(default)]
     symlinks: Vec<Symlink>,
 
     #[serde(default)]
     shell: Vec<ShellCommand>,
+
+    #[serde(default)]
+    script: Vec<Script>,
+}
+
+impl Group {
+    pub fn requires(&self) -> &[String] {
+        &self.requires
+    }
+
+    /// Whether applying the group needs brew.
+    pub fn needs_homebrew(&self, context: &Context) -> bool {
+        !self.taps.is_empty() || !self.brew.is_empty() || self.packages.needs_homebrew(context)
+    }
+
+    pub fn taps(&self) -> &Taps {
+        &self.taps
+    }
+
+    pub fn brews(&self) -> &Brews {
+        &self.brew
+    }
+
+    /// Lets the steps that need it know which group they are part of.
+    pub fn set_name(&mut self, name: &str) {
+        for script in self.script.iter_mut() {
+            script.set_group(name);
+        }
+    }
+
+    /// The kinds of steps in the order they are executed in, rollback walks them backwards.
+    fn sections(&self) -> [&dyn Command; 10] {
+        [
+            &self.taps,
+            &self.brew,
+            &self.packages,
+            &self.cargo,
+            &self.npm,
+            &self.pipx,
+            &self.go,
+            &self.symlinks,
+            &self.shell,
+            &self.script,
+        ]
+    }
 }
 
 impl Command for Group {
     fn execute(&self, context: &Context) -> Result<()> {
-        self.brew.execute(&context)?;
-        self.symlinks.execute(&context)?;
-        self.shell.execute(&context)?;
+        for section in self.sections().iter() {
+            section.execute(context)?;
+        }
         Ok(())
     }
 
     fn rollback(&self, context: &Context) -> Result<()> {
-        self.brew.rollback(&context)?;
-        self.symlinks.rollback(&context)?;
-        self.shell.rollback(&context)?;
+        for section in self.sections().iter().rev() {
+            section.rollback(context)?;
+        }
         Ok(())
     }
 
     fn explain(&self, context: &Context) -> Result<Vec<Explanation>> {
         let mut explanations = Vec::new();
-        explanations.append(&mut self.brew.explain(&context)?);
-        explanations.append(&mut self.symlinks.explain(&context)?);
-        explanations.append(&mut self.shell.explain(&context)?);
+        for section in context.direction.order(&self.sections()) {
+            explanations.append(&mut section.explain(context)?);
+        }
 
         Ok(explanations)
     }
+
+    fn steps(&self) -> Vec<&dyn Command> {
+        self.sections().iter().flat_map(|section| section.steps()).collect()
+    }
 }
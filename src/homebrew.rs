@@ -1,111 +1,803 @@
+use crate::packages::{self, Provider};
+use crate::process::{is_default, retry, which, Invocation, Output, RetryPolicy};
+use crate::state::Resource;
+use crate::transaction;
 use crate::{Command, Context, Explanation, Direction, Result};
-use std::ffi::OsStr;
-use std::process::{self, ExitStatus};
-use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+use std::process::ExitStatus;
+use std::path::PathBuf;
+use std::time::Duration;
 
-pub fn is_homebrew_installed() -> bool {
-    Path::new("/usr/local/bin/brew").exists()
+/// Where the Homebrew installer puts brew on Intel Macs, Apple Silicon and Linux.
+const KNOWN_LOCATIONS: &[&str] = &[
+    "/usr/local/bin/brew",
+    "/opt/homebrew/bin/brew",
+    "/home/linuxbrew/.linuxbrew/bin/brew",
+    "$HOME/.linuxbrew/bin/brew",
+];
+
+/// Finds the brew binary: the `brew_path` setting if there is one, otherwise the first brew on
+/// `PATH` or in one of the known locations.
+pub fn locate(context: &Context) -> Result<Option<PathBuf>> {
+    if let Some(brew_path) = &context.settings.brew_path {
+        let path = context.interpolate(brew_path);
+        if !path.is_file() {
+            return Err(format_err!("brew_path is set to {}, which does not exist", path.display()));
+        }
+        return Ok(Some(path));
+    }
+
+    Ok(which("brew", &context.environment).or_else(|| {
+        KNOWN_LOCATIONS
+            .iter()
+            .map(|location| PathBuf::from(context.expand(location)))
+            .find(|candidate| candidate.is_absolute() && candidate.is_file())
+    }))
 }
 
-pub fn install_homebrew() -> bool {
-    let body = reqwest::get("https://raw.githubusercontent.com/Homebrew/install/master/install")
-        .expect("URL was weird")
-        .text()
-        .expect("could not get stuff");
+/// The official installer, used unless the `homebrew_installer` setting points somewhere else.
+const INSTALLER: &str = "https://raw.githubusercontent.com/Homebrew/install/HEAD/install.sh";
 
-    let status = std::process::Command::new("ruby").args(&["-e", &body]).status().expect("it bombed out?");
+fn installer(context: &Context) -> &str {
+    context.settings.homebrew_installer.as_ref().map_or(INSTALLER, String::as_str)
+}
 
-    status.success()
+/// What installing Homebrew would do, for `--explain`.
+pub fn explain_install_homebrew(context: &Context) -> Explanation {
+    match context.settings.homebrew_installer_sha256 {
+        Some(_) => Explanation::new(format!("Homebrew is missing, will check and run the installer from {}", installer(context))),
+        None => Explanation::new("Homebrew is missing and can not be installed without homebrew_installer_sha256 in the settings"),
+    }
 }
 
-fn brew(source: BrewSoure) -> process::Command {
-    let mut command = process::Command::new("brew");
+/// Runs the Homebrew installer, but only if it matches the checksum pinned in the settings.
+pub fn install_homebrew(context: &Context) -> Result<()> {
+    let expected = context.settings.homebrew_installer_sha256.as_ref().ok_or_else(|| {
+        BrewError::Bootstrap("refusing to run the installer without homebrew_installer_sha256 in the settings".to_string())
+    })?;
+
+    let source = installer(context);
+    let script = fetch(source, context).map_err(|e| BrewError::Bootstrap(format!("could not get {}: {}", source, e)))?;
+
+    let actual = sha256(&script);
+    if !actual.eq_ignore_ascii_case(expected.trim()) {
+        return Err(BrewError::Bootstrap(format!("{} has the sha256 {}, expected {}", source, actual, expected)).into());
+    }
+
+    let script = String::from_utf8(script).map_err(|e| BrewError::Bootstrap(format!("{} is not a script: {}", source, e)))?;
+    let mut environment = context.environment.clone();
+    environment.insert("NONINTERACTIVE".to_string(), "1".to_string());
+    let invocation = Invocation::new("/bin/bash").arg("-c").arg(script).environment(environment);
+
+    let status = context
+        .runner
+        .run(&invocation)
+        .map_err(|e| BrewError::Bootstrap(e.to_string()))?
+        .status;
+
+    if !status.success() {
+        return Err(BrewError::Bootstrap(format!("the installer failed with {}", status)).into());
+    }
+    Ok(())
+}
+
+/// Downloads `source` if it is a URL, otherwise reads it relative to the working directory.
+fn fetch(source: &str, context: &Context) -> Result<Vec<u8>> {
+    let mut content = Vec::new();
+    if source.starts_with("https://") || source.starts_with("http://") {
+        reqwest::get(source)?.error_for_status()?.copy_to(&mut content)?;
+    } else {
+        content = fs::read(context.interpolate(source))?;
+    }
+    Ok(content)
+}
+
+fn sha256(content: &[u8]) -> String {
+    Sha256::digest(content).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Treats brew not being there as nothing being installed, so `--explain` works before Homebrew is.
+pub fn unless_missing<T: Default>(result: Result<T>) -> Result<T> {
+    match result {
+        Err(error) => match error.downcast_ref::<BrewError>() {
+            Some(BrewError::NotFound) => Ok(T::default()),
+            _ => Err(error),
+        },
+        ok => ok,
+    }
+}
+
+#[derive(Fail, Debug)]
+pub enum BrewError {
+    #[fail(display = "Could not find the brew binary, is Homebrew installed?")]
+    NotFound,
+    #[fail(display = "brew {} {} failed with {}:\n{}", action, name, status, stderr)]
+    Failed {
+        action: &'static str,
+        name: String,
+        status: ExitStatus,
+        stderr: String,
+    },
+    #[fail(display = "\"{}\" failed with {}:\n{}", command, status, stderr)]
+    ListFailed {
+        command: String,
+        status: ExitStatus,
+        stderr: String,
+    },
+    #[fail(display = "Homebrew does not know a package called {}", _0)]
+    UnknownPackage(String),
+    #[fail(display = "Could not install Homebrew: {}", _0)]
+    Bootstrap(String),
+}
+
+/// What brew says on stderr when it can not find a formula or cask.
+const UNKNOWN_PACKAGE: &[&str] = &["No available formula", "No formulae found", "No cask with this name", "No formulae or casks found"];
+
+/// `brew <action>`, with `--cask` for casks.
+fn brew(action: &str, source: BrewSoure, context: &Context) -> Invocation {
+    let command = Invocation::new(context.brew.to_string_lossy()).arg(action);
     match source {
-        BrewSoure::Cask => {
-            command.arg("cask");
+        BrewSoure::Cask => command.arg("--cask"),
+        BrewSoure::Regular => command,
+    }
+}
+
+fn install(name: &str, cask: BrewSoure, policy: &RetryPolicy, context: &Context) -> Result<()> {
+    run("install", name, cask, policy, context)
+}
+
+fn remove(name: &str, cask: BrewSoure, policy: &RetryPolicy, context: &Context) -> Result<()> {
+    match cask {
+        Cask => run("uninstall", name, cask, policy, context),
+        Regular => run("remove", name, cask, policy, context),
+    }
+}
+
+fn run(action: &'static str, name: &str, cask: BrewSoure, policy: &RetryPolicy, context: &Context) -> Result<()> {
+    attempt(action, name, brew(action, cask, context).arg(name), policy, context)
+}
+
+/// Runs `invocation`, which does `action` to `name`, until it succeeds or `policy` gives up.
+fn attempt(action: &'static str, name: &str, invocation: Invocation, policy: &RetryPolicy, context: &Context) -> Result<()> {
+    let invocation = invocation.timeout(policy.timeout());
+    // Retrying won't make an unknown package known, so that stops the retries and is reported after them.
+    let mut unknown = false;
+    retry(policy, || {
+        let finished = context.runner.run(&invocation).map_err(not_found)?;
+
+        if finished.status.success() {
+            return Ok(());
+        }
+
+        if UNKNOWN_PACKAGE.iter().any(|message| finished.stderr.contains(message)) {
+            unknown = true;
+            return Ok(());
         }
-        _ => (),
+
+        Err(BrewError::Failed {
+            action,
+            name: name.to_string(),
+            status: finished.status,
+            stderr: finished.stderr,
+        }.into())
+    })?;
+
+    if unknown {
+        return Err(BrewError::UnknownPackage(name.to_string()).into());
     }
-    command
+    Ok(())
 }
 
-// TODO better error reporting here!
-fn install<S: AsRef<OsStr>>(name: S, cask: BrewSoure) -> ExitStatus {
-    brew(cask)
-        .arg("install")
-        .arg(name)
-        .status()
-        .expect("Homebrew: could not install package")
+/// Everything brew lists as installed from `source`, with the versions of it that are installed.
+fn list(source: BrewSoure, context: &Context) -> Result<HashMap<String, Vec<String>>> {
+    let listing = capture(brew("list", source, context).arg("--versions"), context)?;
+
+    Ok(listing
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            let name = words.next()?.to_lowercase();
+            Some((name, words.map(String::from).collect()))
+        })
+        .collect())
 }
 
-// TODO better error reporting here!
-fn remove<S: AsRef<OsStr>>(name: S, cask: BrewSoure) -> ExitStatus {
-    brew(cask)
-        .arg("remove")
-        .arg(name.as_ref())
-        .status()
-        .expect("Homebrew: could not remove package")
+/// Formulae with a newer version out, and that version. Brew lists them as `nvim (0.8.0) < 0.9.1`.
+fn outdated(context: &Context) -> Result<HashMap<String, String>> {
+    let listing = capture(brew("outdated", Regular, context).arg("--verbose"), context)?;
+
+    Ok(listing
+        .lines()
+        .filter_map(|line| {
+            let line = line.split(" [").next()?;
+            let name = line.split_whitespace().next()?.to_lowercase();
+            let latest = line.split_whitespace().last()?.to_string();
+            Some((name, latest))
+        })
+        .collect())
 }
 
-// TODO better error reporting here!
-fn ls<S: AsRef<OsStr>>(name: S, cask: BrewSoure) -> BrewStatus {
-    let status = brew(cask)
-        .arg("ls")
-        .arg("--versions")
-        .arg(name.as_ref())
-        .status()
-        .expect("Homebrew: Could not check if package present");
+/// The first word of every line `invocation` prints, lowercased.
+fn names(invocation: Invocation, context: &Context) -> Result<HashSet<String>> {
+    Ok(capture(invocation, context)?
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .map(str::to_lowercase)
+        .collect())
+}
 
-    if status.success() {
-        BrewStatus::Installed
-    } else {
-        BrewStatus::Missing
+/// What `invocation` prints, which is expected to succeed.
+fn capture(invocation: Invocation, context: &Context) -> Result<String> {
+    let invocation = invocation.output(Output::Capture);
+    let finished = context.runner.run(&invocation).map_err(not_found)?;
+
+    if !finished.status.success() {
+        return Err(BrewError::ListFailed {
+            command: invocation.to_string(),
+            status: finished.status,
+            stderr: finished.stderr,
+        }.into());
+    }
+
+    Ok(finished.stdout)
+}
+
+/// Installs `brews` with a single brew invocation, falling back to one at a time to find out which
+/// of them failed.
+fn install_together(brews: &[&Brew], source: BrewSoure, context: &Context) -> Result<()> {
+    let find = |name: &String| brews.iter().find(|brew| brew.name() == *name);
+    let names: Vec<String> = brews.iter().map(|brew| brew.name()).collect();
+    let manager = match source {
+        Cask => "brew-cask",
+        Regular => "brew",
+    };
+
+    let install = |names: &[String]| match names {
+        [name] => install(name, source, &find(name).map(|brew| brew.policy()).unwrap_or_default(), context),
+        _ => {
+            let timeout = names.iter().filter_map(find).map(|brew| brew.policy().timeout()).sum::<Option<Duration>>();
+            let policy = RetryPolicy { timeout_secs: timeout.map(|timeout| timeout.as_secs()), ..RetryPolicy::default() };
+            attempt("install", &names.join(" "), brew("install", source, context).args(names), &policy, context)
+        }
+    };
+    let installed = |names: &[String]| {
+        let listed = list(source, context)?;
+        Ok(names
+            .iter()
+            .filter(|name| find(name).is_some_and(|brew| listed.contains_key(&brew.listed_name())))
+            .cloned()
+            .collect())
+    };
+
+    packages::install_together(manager, &names, context, install, installed)
+}
+
+/// Turns failing to start brew at all into `BrewError::NotFound`.
+fn not_found(error: failure::Error) -> failure::Error {
+    match error.downcast_ref::<io::Error>() {
+        Some(io_error) if io_error.kind() == io::ErrorKind::NotFound => BrewError::NotFound.into(),
+        _ => error,
+    }
+}
+
+/// Uninstalls a package recorded in the state journal, `manager` is what `Brew::manager` or
+/// `Tap::MANAGER` was for it.
+pub fn uninstall(manager: &str, name: &str, context: &Context) -> Result<()> {
+    match manager {
+        Tap::MANAGER => untap(name, context),
+        "brew-cask" => remove(name, Cask, &RetryPolicy::default(), context),
+        _ => remove(name, Regular, &RetryPolicy::default(), context),
+    }
+}
+
+fn untap(name: &str, context: &Context) -> Result<()> {
+    attempt("untap", name, brew("untap", Regular, context).arg(name), &RetryPolicy::default(), context)
+}
+
+/// Homebrew as the provider of a `packages` step, which only installs formulae.
+pub struct Homebrew;
+
+impl Provider for Homebrew {
+    fn name(&self) -> &'static str {
+        "brew"
+    }
+
+    fn installed(&self, context: &Context) -> Result<HashSet<String>> {
+        Ok(list(Regular, context)?.into_keys().collect())
+    }
+
+    fn install(&self, names: &[String], context: &Context) -> Result<()> {
+        let invocation = brew("install", Regular, context).args(names);
+        attempt("install", &names.join(" "), invocation, &RetryPolicy::default(), context)
+    }
+
+    fn remove(&self, name: &str, context: &Context) -> Result<()> {
+        remove(name, Regular, &RetryPolicy::default(), context)
+    }
+}
+
+/// A custom tap, cloned from `url` rather than GitHub.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CustomTap {
+    name: String,
+    url: String,
+}
+
+/// A third-party repository to install brews from.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum Tap {
+    Simple(String),
+    Custom(CustomTap),
+}
+
+impl Tap {
+    /// How taps are named in the state journal.
+    const MANAGER: &'static str = "brew-tap";
+
+    pub fn new(name: String, url: Option<String>) -> Tap {
+        match url {
+            Some(url) => Tap::Custom(CustomTap { name, url }),
+            None => Tap::Simple(name),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Tap::Simple(name) => name,
+            Tap::Custom(CustomTap { name, .. }) => name,
+        }
+    }
+
+    pub fn url(&self) -> Option<&str> {
+        match self {
+            Tap::Simple(_) => None,
+            Tap::Custom(CustomTap { url, .. }) => Some(url),
+        }
+    }
+
+    fn tap(&self, context: &Context) -> Result<()> {
+        let invocation = brew("tap", Regular, context).arg(self.name()).args(self.url());
+        attempt("tap", self.name(), invocation, &RetryPolicy::default(), context)
+    }
+
+    fn explanation(&self, tapped: bool, context: &Context) -> Explanation {
+        let added_by_dotter = context.state.borrow().installed_by_dotter(Tap::MANAGER, self.name());
+        let message = match (tapped, &context.direction) {
+            (true, Direction::Execute) => format!("{} is already tapped, nothing to do", self.name()),
+            (false, Direction::Execute) => match self.url() {
+                Some(url) => format!("Will tap {} from {}", self.name(), url),
+                None => format!("Will tap {}", self.name()),
+            },
+            (true, Direction::Rollback) if added_by_dotter => format!("Will untap {}.", self.name()),
+            (true, Direction::Rollback) => format!("{} was not tapped by dotter, leaving it alone.", self.name()),
+            (false, Direction::Rollback) => format!("{} is not tapped, nothing to do.", self.name()),
+        };
+
+        Explanation::new(message)
+    }
+}
+
+/// The taps of a group, added before any of its brews are installed.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(transparent)]
+pub struct Taps(Vec<Tap>);
+
+impl Taps {
+    pub fn iter(&self) -> impl Iterator<Item = &Tap> {
+        self.0.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn tapped(&self, context: &Context) -> Result<HashSet<String>> {
+        names(brew("tap", Regular, context), context)
+    }
+
+    /// Taps what is not tapped yet.
+    fn add(&self, context: &Context) -> Result<()> {
+        let tapped = self.tapped(context)?;
+        for tap in &self.0 {
+            if tapped.contains(&tap.name().to_lowercase()) {
+                context.state.borrow_mut().found_package(Tap::MANAGER, tap.name());
+            } else {
+                tap.tap(context)?;
+                context.state.borrow_mut().installed_package(Tap::MANAGER, tap.name());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Command for Taps {
+    fn execute(&self, context: &Context) -> Result<()> {
+        if self.0.is_empty() {
+            return Ok(());
+        }
+
+        let packages: Vec<(&str, String)> = self.0.iter().map(|tap| (Tap::MANAGER, tap.name().to_string())).collect();
+        transaction::contain(&packages, context, || self.add(context), |manager, name| uninstall(manager, name, context))
+    }
+
+    fn rollback(&self, context: &Context) -> Result<()> {
+        for tap in self.0.iter().rev() {
+            if context.state.borrow().installed_by_dotter(Tap::MANAGER, tap.name()) {
+                untap(tap.name(), context)?;
+                context.state.borrow_mut().removed_package(Tap::MANAGER, tap.name());
+            }
+        }
+        Ok(())
+    }
+
+    fn explain(&self, context: &Context) -> Result<Vec<Explanation>> {
+        if self.0.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let tapped = unless_missing(self.tapped(context))?;
+        Ok(context
+            .direction
+            .order(&self.0)
+            .map(|tap| tap.explanation(tapped.contains(&tap.name().to_lowercase()), context))
+            .collect())
+    }
+
+    fn steps(&self) -> Vec<&dyn Command> {
+        vec![self]
+    }
+
+    fn resources(&self, _context: &Context) -> Vec<Resource> {
+        self.0.iter().map(|tap| Resource::Package(Tap::MANAGER.to_string(), tap.name().to_string())).collect()
     }
 }
 
-#[derive(Deserialize, Debug)]
+/// When an installed formula should be upgraded.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Upgrade {
+    /// Run `brew upgrade` on every run, brew decides whether there is anything to do.
+    Always,
+    #[default]
+    Never,
+    /// Only upgrade when `brew outdated` lists the formula.
+    Outdated,
+}
+
+/// Which version of a formula to keep and how to treat newer ones.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct Versioning {
+    /// Installs the versioned formula `name@version`, like `postgresql@14`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
+
+    /// Runs `brew pin` after installing, and never upgrades the formula.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pin: bool,
+
+    #[serde(default, skip_serializing_if = "is_default")]
+    upgrade: Upgrade,
+}
+
+impl Versioning {
+    fn versioned(&self, name: &str) -> String {
+        match &self.version {
+            Some(version) => format!("{}@{}", name, version),
+            None => name.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
 pub struct TappedBrew {
     tap: String,
     name: String,
+
+    #[serde(flatten)]
+    versioning: Versioning,
+
+    #[serde(flatten)]
+    policy: RetryPolicy,
+}
+
+impl TappedBrew {
+    pub fn new<S: Into<String>>(tap: S, name: S) -> TappedBrew {
+        TappedBrew {
+            tap: tap.into(),
+            name: name.into(),
+            versioning: Versioning::default(),
+            policy: RetryPolicy::default(),
+        }
+    }
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct CaskBrew {
     cask: String,
+
+    #[serde(flatten)]
+    policy: RetryPolicy,
+}
+
+impl CaskBrew {
+    pub fn new<S: Into<String>>(cask: S) -> CaskBrew {
+        CaskBrew { cask: cask.into(), policy: RetryPolicy::default() }
+    }
 }
 
-#[derive(Deserialize, Debug)]
+/// A regular formula written out as an object, to pin its version or give it a timeout or retries.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FormulaBrew {
+    name: String,
+
+    #[serde(flatten)]
+    versioning: Versioning,
+
+    #[serde(flatten)]
+    policy: RetryPolicy,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
 #[serde(untagged)]
 pub enum Brew {
     Simple(String),
     FromTap(TappedBrew),
     FromCask(CaskBrew),
+    Formula(FormulaBrew),
 }
 
 impl Brew {
-    fn name(&self) -> String {
+    /// The full name to install the package by, including the tap it comes from.
+    pub fn name(&self) -> String {
+        match self {
+            Brew::FromTap(TappedBrew { tap, .. }) => format!("{}/{}", tap, self.listed_name()),
+            _ => self.listed_name(),
+        }
+    }
+
+    fn policy(&self) -> RetryPolicy {
+        match self {
+            Brew::Simple(_) => RetryPolicy::default(),
+            Brew::FromTap(TappedBrew { policy, .. }) => policy.clone(),
+            Brew::FromCask(CaskBrew { policy, .. }) => policy.clone(),
+            Brew::Formula(FormulaBrew { policy, .. }) => policy.clone(),
+        }
+    }
+
+    /// The name brew lists the package under once it is installed.
+    fn listed_name(&self) -> String {
         match self {
             Brew::Simple(name) => name.clone(),
-            Brew::FromTap(TappedBrew {tap, name}) => format!("{}/{}", tap, name),
-            Brew::FromCask(CaskBrew {cask}) => cask.clone(),
+            Brew::FromTap(TappedBrew { name, versioning, .. }) => versioning.versioned(name),
+            Brew::FromCask(CaskBrew { cask, .. }) => cask.clone(),
+            Brew::Formula(FormulaBrew { name, versioning, .. }) => versioning.versioned(name),
+        }
+    }
+
+    fn versioning(&self) -> Versioning {
+        match self {
+            Brew::FromTap(TappedBrew { versioning, .. }) => versioning.clone(),
+            Brew::Formula(FormulaBrew { versioning, .. }) => versioning.clone(),
+            _ => Versioning::default(),
+        }
+    }
+
+    /// When to upgrade the brew once it is installed, pinned ones never are.
+    fn upgrade(&self) -> Upgrade {
+        let versioning = self.versioning();
+        if versioning.pin {
+            Upgrade::Never
+        } else {
+            versioning.upgrade
         }
     }
 
-    fn status(&self) -> BrewStatus {
+    pub fn tap(&self) -> Option<&str> {
+        match self {
+            Brew::FromTap(TappedBrew { tap, .. }) => Some(tap),
+            _ => None,
+        }
+    }
+
+    pub fn is_cask(&self) -> bool {
+        self.source() == Cask
+    }
+
+    fn source(&self) -> BrewSoure {
         match self {
-            Brew::Simple(name) => ls(name, Regular),
-            Brew::FromCask(CaskBrew { cask }) => ls(cask, Cask),
-            Brew::FromTap(TappedBrew { tap: _, name }) => ls(name, Regular),
+            Brew::FromCask(_) => Cask,
+            _ => Regular,
+        }
+    }
+
+    /// How packages from this entry are named in the state journal.
+    fn manager(&self) -> &'static str {
+        match self {
+            Brew::FromCask(_) => "brew-cask",
+            _ => "brew",
+        }
+    }
+
+    fn explanation(&self, installed: &Installed, context: &Context) -> Explanation {
+        let installed_by_dotter = context.state.borrow().installed_by_dotter(self.manager(), &self.name());
+        let pin = self.versioning().pin;
+        let message = match (installed.status(self), &context.direction) {
+            (BrewStatus::Installed(versions), Direction::Execute) => {
+                let current = describe(&self.name(), &versions);
+                match installed.newer(self) {
+                    _ if pin => format!("{} is pinned, skipping", current),
+                    Some(latest) if self.upgrade() != Upgrade::Never => format!("Will upgrade {} -> {}", current, latest),
+                    _ if self.upgrade() != Upgrade::Never => format!("{} is up to date, nothing to do", current),
+                    _ => format!("{} is already installed, nothing to do", current),
+                }
+            }
+            (BrewStatus::Missing, Direction::Execute) if pin => format!("Will install {} and pin it", self.name()),
+            (BrewStatus::Missing, Direction::Execute) => format!("Will install {}", self.name()),
+            (BrewStatus::Installed(_), Direction::Rollback) if installed_by_dotter => format!("WIll uninstall {}.", self.name()),
+            (BrewStatus::Installed(_), Direction::Rollback) => format!("{} was not installed by dotter, leaving it alone.", self.name()),
+            (BrewStatus::Missing, Direction::Rollback) => format!("{} is not installed, nothing to do.", self.name())
+        };
+
+        Explanation::new(message)
+    }
+}
+
+/// `name` followed by the versions of it that are installed, if brew listed any.
+fn describe(name: &str, versions: &[String]) -> String {
+    if versions.is_empty() {
+        name.to_string()
+    } else {
+        format!("{} {}", name, versions.join(", "))
+    }
+}
+
+/// Brings `brews` up to date: installs what is missing, upgrades and pins what asks for it.
+fn apply(brews: &[Brew], context: &Context) -> Result<()> {
+    let check_outdated = brews.iter().any(|brew| brew.upgrade() == Upgrade::Outdated);
+    let installed = Installed::query(brews, check_outdated, context)?;
+
+    for source in [Regular, Cask].iter() {
+        let (present, missing): (Vec<&Brew>, Vec<&Brew>) = brews
+            .iter()
+            .filter(|brew| brew.source() == *source)
+            .partition(|brew| installed.status(brew) != BrewStatus::Missing);
+
+        for brew in &present {
+            context.state.borrow_mut().found_package(brew.manager(), &brew.name());
+
+            let upgrade = match brew.upgrade() {
+                Upgrade::Always => true,
+                Upgrade::Outdated => installed.newer(brew).is_some(),
+                Upgrade::Never => false,
+            };
+            if upgrade {
+                run("upgrade", &brew.name(), *source, &brew.policy(), context)?;
+            }
+        }
+        install_together(&missing, *source, context)?;
+
+        for brew in present.iter().chain(missing.iter()) {
+            if brew.versioning().pin && !installed.is_pinned(brew) {
+                run("pin", &brew.name(), Regular, &RetryPolicy::default(), context)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// What brew lists as installed, asked for once for a whole group of brews.
+#[derive(Default)]
+struct Installed {
+    formulae: HashMap<String, Vec<String>>,
+    casks: HashMap<String, Vec<String>>,
+    outdated: HashMap<String, String>,
+    pinned: HashSet<String>,
+}
+
+impl Installed {
+    /// Lists what `brews` need to know about, which formulae are outdated only if `check_outdated` is set.
+    fn query(brews: &[Brew], check_outdated: bool, context: &Context) -> Result<Installed> {
+        let wanted = |source| brews.iter().any(|brew| brew.source() == source);
+        let mut installed = Installed::default();
+
+        if wanted(Regular) {
+            installed.formulae = list(Regular, context)?;
+        }
+        if wanted(Cask) {
+            installed.casks = list(Cask, context)?;
+        }
+        if check_outdated {
+            installed.outdated = outdated(context)?;
+        }
+        if brews.iter().any(|brew| brew.versioning().pin) {
+            installed.pinned = names(brew("list", Regular, context).arg("--pinned"), context)?;
+        }
+        Ok(installed)
+    }
+
+    fn status(&self, brew: &Brew) -> BrewStatus {
+        let listed = match brew.source() {
+            Regular => &self.formulae,
+            Cask => &self.casks,
+        };
+
+        match listed.get(&brew.listed_name().to_lowercase()) {
+            Some(versions) => BrewStatus::Installed(versions.clone()),
+            None => BrewStatus::Missing,
         }
     }
+
+    /// The newer version brew knows of, if `brew outdated` was asked.
+    fn newer(&self, brew: &Brew) -> Option<&String> {
+        self.outdated.get(&brew.listed_name().to_lowercase())
+    }
+
+    fn is_pinned(&self, brew: &Brew) -> bool {
+        self.pinned.contains(&brew.listed_name().to_lowercase())
+    }
+}
+
+/// The brews of a group. Brew is slow to start, so they are checked and installed together rather
+/// than one process per package.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(transparent)]
+pub struct Brews(Vec<Brew>);
+
+impl Brews {
+    pub fn iter(&self) -> impl Iterator<Item = &Brew> {
+        self.0.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl Command for Brews {
+    fn execute(&self, context: &Context) -> Result<()> {
+        let packages: Vec<(&str, String)> = self.0.iter().map(|brew| (brew.manager(), brew.name())).collect();
+        transaction::contain(&packages, context, || apply(&self.0, context), |manager, name| uninstall(manager, name, context))
+    }
+
+    fn rollback(&self, context: &Context) -> Result<()> {
+        self.0.rollback(context)
+    }
+
+    fn explain(&self, context: &Context) -> Result<Vec<Explanation>> {
+        if self.0.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let check_outdated = self.0.iter().any(|brew| brew.upgrade() != Upgrade::Never);
+        let installed = unless_missing(Installed::query(&self.0, check_outdated, context))?;
+        Ok(context
+            .direction
+            .order(&self.0)
+            .map(|brew| brew.explanation(&installed, context))
+            .collect())
+    }
+
+    fn steps(&self) -> Vec<&dyn Command> {
+        vec![self]
+    }
+
+    fn resources(&self, context: &Context) -> Vec<Resource> {
+        self.0.iter().flat_map(|brew| brew.resources(context)).collect()
+    }
 }
 
 #[derive(PartialEq, Eq, Debug)]
 pub enum BrewStatus {
-    Installed,
+    /// With the versions brew lists for it, there can be several side by side.
+    Installed(Vec<String>),
     Missing,
 }
 
+#[derive(Clone, Copy, PartialEq)]
 enum BrewSoure {
     Regular,
     Cask,
@@ -114,51 +806,32 @@ enum BrewSoure {
 use crate::homebrew::BrewSoure::{Cask, Regular};
 
 impl Command for Brew {
-    fn execute(&self, _context: &Context) -> Result<()> {
-        if self.status() == BrewStatus::Installed {
-            return Ok(());
-        }
+    fn execute(&self, context: &Context) -> Result<()> {
+        apply(std::slice::from_ref(self), context)
+    }
 
-        match self {
-            Brew::Simple(name) => {
-                install(name, Regular);
-            }
-            Brew::FromCask(CaskBrew { cask }) => {
-                install(cask, Cask);
-            }
-            Brew::FromTap(TappedBrew { tap, name }) => {
-                let full_name = format!("{}/{}", tap, name);
-                install(full_name, Regular);
-            }
+    fn rollback(&self, context: &Context) -> Result<()> {
+        if !context.state.borrow().installed_by_dotter(self.manager(), &self.name()) {
+            return Ok(());
         }
 
+        remove(&self.name(), self.source(), &self.policy(), context)?;
+        context.state.borrow_mut().removed_package(self.manager(), &self.name());
         Ok(())
     }
 
-    fn rollback(&self, _context: &Context) -> Result<()> {
-        match self {
-            Brew::Simple(name) => {
-                remove(name, Regular);
-            }
-            Brew::FromCask(CaskBrew { cask }) => {
-                remove(cask, Cask);
-            }
-            Brew::FromTap(TappedBrew { tap: _tap, name }) => {
-                remove(name, Regular);
-            }
-        }
-        Ok(())
+    fn explain(&self, context: &Context) -> Result<Vec<Explanation>> {
+        let brews = std::slice::from_ref(self);
+        let installed = unless_missing(Installed::query(brews, self.upgrade() != Upgrade::Never, context))?;
+        Ok(vec![self.explanation(&installed, context)])
     }
 
-    fn explain(&self, context: &Context) -> Result<Vec<Explanation>> {
-        let message = match (self.status(), &context.direction) {
-            (BrewStatus::Installed, Direction::Execute)  => format!("{} is already installed, nothing to do", self.name()),
-            (BrewStatus::Missing,   Direction::Execute)  => format!("Will install {}", self.name()),
-            (BrewStatus::Installed, Direction::Rollback) => format!("WIll uninstall {}.", self.name()),
-            (BrewStatus::Missing,   Direction::Rollback)  => format!("{} is not installed, nothing to do.", self.name())
-        };
+    fn steps(&self) -> Vec<&dyn Command> {
+        vec![self]
+    }
 
-        Ok(vec![Explanation::new(message)])
+    fn resources(&self, _context: &Context) -> Vec<Resource> {
+        vec![Resource::Package(self.manager().to_string(), self.name())]
     }
 }
 
@@ -167,6 +840,19 @@ impl Command for Brew {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::process::fake::FakeRunner;
+    use crate::settings::Settings;
+    use maplit::hashmap;
+    use std::rc::Rc;
+
+    fn faked() -> (Rc<FakeRunner>, Context) {
+        let runner = Rc::new(FakeRunner::default());
+        let context = Context {
+            runner: runner.clone(),
+            ..Context::default()
+        };
+        (runner, context)
+    }
 
     #[test]
     #[ignore]
@@ -176,10 +862,10 @@ mod tests {
         let brew = Brew::Simple("parallel".to_string());
 
         assert_missing(&brew);
-        brew.execute(&context);
+        brew.execute(&context).unwrap();
 
         assert_installed(&brew);
-        brew.rollback(&context);
+        brew.rollback(&context).unwrap();
 
         assert_missing(&brew);
     }
@@ -191,13 +877,14 @@ mod tests {
 
         let brew_cask = Brew::FromCask(CaskBrew {
             cask: "couleurs".to_string(),
+            policy: RetryPolicy::default(),
         });
 
         assert_missing(&brew_cask);
-        brew_cask.execute(&context);
+        brew_cask.execute(&context).unwrap();
 
         assert_installed(&brew_cask);
-        brew_cask.rollback(&context);
+        brew_cask.rollback(&context).unwrap();
 
         assert_missing(&brew_cask);
     }
@@ -210,25 +897,479 @@ mod tests {
         let brew_cask = Brew::FromTap(TappedBrew {
             tap: "brewsci/bio".to_string(),
             name: "abacas".to_string(),
+            versioning: Versioning::default(),
+            policy: RetryPolicy::default(),
         });
 
         assert_missing(&brew_cask);
-        brew_cask.execute(&context);
+        brew_cask.execute(&context).unwrap();
 
         assert_installed(&brew_cask);
-        brew_cask.rollback(&context);
+        brew_cask.rollback(&context).unwrap();
 
         assert_missing(&brew_cask);
     }
 
     #[test]
-    fn explaining_homebrew_commands_shows_what_needs_installing() {}
+    fn explaining_homebrew_commands_shows_what_needs_installing() {
+        let (runner, context) = faked();
+        runner.print("brew list --versions", "git 2.40.0\n");
+
+        let git = Brew::Simple("git".to_string());
+        let nvim = Brew::Simple("nvim".to_string());
+
+        assert_eq!(git.explain(&context).unwrap()[0].message, "git 2.40.0 is already installed, nothing to do");
+        assert_eq!(nvim.explain(&context).unwrap()[0].message, "Will install nvim");
+        assert_eq!(runner.commands(), vec!["brew list --versions", "brew list --versions"]);
+    }
+
+    #[test]
+    fn missing_brews_are_installed_and_recorded() {
+        let (runner, context) = faked();
+
+        Brew::Simple("nvim".to_string()).execute(&context).unwrap();
+
+        assert_eq!(runner.commands(), vec!["brew list --versions", "brew install nvim"]);
+        assert!(context.state.borrow().installed_by_dotter("brew", "nvim"));
+    }
+
+    #[test]
+    fn installed_brews_are_left_alone() {
+        let (runner, context) = faked();
+        runner.print("brew list --cask --versions", "iterm2 3.4.19\n");
+        let cask = Brew::FromCask(CaskBrew {
+            cask: "iterm2".to_string(),
+            policy: RetryPolicy::default(),
+        });
+
+        cask.execute(&context).unwrap();
+
+        assert_eq!(runner.commands(), vec!["brew list --cask --versions"]);
+        assert!(!context.state.borrow().installed_by_dotter("brew-cask", "iterm2"));
+    }
+
+    #[test]
+    fn casks_are_uninstalled_with_the_cask_flag() {
+        let (runner, context) = faked();
+        context.state.borrow_mut().installed_package("brew-cask", "iterm2");
+
+        Brew::FromCask(CaskBrew::new("iterm2")).rollback(&context).unwrap();
+
+        assert_eq!(runner.commands(), vec!["brew uninstall --cask iterm2"]);
+        assert!(!context.state.borrow().installed_by_dotter("brew-cask", "iterm2"));
+    }
+
+    #[test]
+    fn only_brews_installed_by_dotter_are_rolled_back() {
+        let (runner, context) = faked();
+        context.state.borrow_mut().installed_package("brew", "nvim");
+        context.state.borrow_mut().found_package("brew", "git");
+
+        Brew::Simple("git".to_string()).rollback(&context).unwrap();
+        Brew::Simple("nvim".to_string()).rollback(&context).unwrap();
+
+        assert_eq!(runner.commands(), vec!["brew remove nvim"]);
+        assert!(!context.state.borrow().installed_by_dotter("brew", "nvim"));
+    }
+
+    #[test]
+    fn unknown_packages_are_reported_as_such() {
+        let (runner, context) = faked();
+        runner.respond("brew install nvmi", 1, "Error: No available formula with the name \"nvmi\".");
+
+        let error = Brew::Simple("nvmi".to_string()).execute(&context).unwrap_err();
+
+        match error.downcast_ref::<BrewError>() {
+            Some(BrewError::UnknownPackage(name)) => assert_eq!(name, "nvmi"),
+            other => panic!("expected BrewError::UnknownPackage, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unknown_packages_are_not_retried() {
+        let (runner, context) = faked();
+        runner.respond("brew install nvmi", 1, "Error: No available formula with the name \"nvmi\".");
+        let brew: Brew = serde_json::from_str(r#"{"name": "nvmi", "retries": 2, "retry_delay_secs": 0}"#).unwrap();
+
+        let error = brew.execute(&context).unwrap_err();
+
+        assert!(matches!(error.downcast_ref::<BrewError>(), Some(BrewError::UnknownPackage(_))));
+        assert_eq!(runner.commands().iter().filter(|command| *command == "brew install nvmi").count(), 1);
+    }
+
+    #[test]
+    fn failed_installs_keep_what_brew_said() {
+        let (runner, context) = faked();
+        runner.respond("brew install nvim", 1, "Error: disk full");
+
+        let error = Brew::Simple("nvim".to_string()).execute(&context).unwrap_err();
+
+        assert_eq!(error.to_string(), "brew install nvim failed with exit status: 1:\nError: disk full");
+        assert!(!context.state.borrow().installed_by_dotter("brew", "nvim"));
+    }
+
+    #[test]
+    fn a_missing_brew_binary_is_reported_as_such() {
+        let error = not_found(io::Error::from(io::ErrorKind::NotFound).into());
+
+        match error.downcast_ref::<BrewError>() {
+            Some(BrewError::NotFound) => (),
+            other => panic!("expected BrewError::NotFound, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn other_errors_are_passed_on() {
+        let error = not_found(io::Error::from(io::ErrorKind::PermissionDenied).into());
+
+        assert!(error.downcast_ref::<BrewError>().is_none());
+    }
+
+    fn brews(json: &str) -> Brews {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn a_group_of_brews_is_listed_once_and_installed_together() {
+        let (runner, context) = faked();
+        runner.print("brew list --versions", "git 2.40.0\nnvim 0.8.0 0.9.1\n");
+        runner.print("brew list --cask --versions", "iterm2 3.4.19\n");
+
+        brews(r#"["git", "nvim", "tmux", "jq", {"cask": "iterm2"}, {"cask": "firefox"}]"#).execute(&context).unwrap();
+
+        assert_eq!(runner.commands(), vec![
+            "brew list --versions",
+            "brew list --cask --versions",
+            "brew install tmux jq",
+            "brew install --cask firefox",
+        ]);
+        let state = context.state.borrow();
+        assert!(state.installed_by_dotter("brew", "jq"));
+        assert!(state.installed_by_dotter("brew-cask", "firefox"));
+        assert!(!state.installed_by_dotter("brew", "git"));
+        assert!(!state.installed_by_dotter("brew-cask", "iterm2"));
+    }
+
+    #[test]
+    fn a_failed_batch_is_retried_one_package_at_a_time() {
+        let (runner, context) = faked();
+        runner.print("brew list --versions", "");
+        runner.print("brew list --versions", "jq 1.7\n");
+        runner.respond("brew install tmux nvmi jq", 1, "Error: No available formula with the name \"nvmi\".");
+        runner.respond("brew install nvmi", 1, "Error: No available formula with the name \"nvmi\".");
+
+        let error = brews(r#"["tmux", "nvmi", "jq"]"#).execute(&context).unwrap_err();
+
+        match error.downcast_ref::<BrewError>() {
+            Some(BrewError::UnknownPackage(name)) => assert_eq!(name, "nvmi"),
+            other => panic!("expected BrewError::UnknownPackage, got {:?}", other),
+        }
+        assert_eq!(runner.commands(), vec![
+            "brew list --versions",
+            "brew install tmux nvmi jq",
+            "brew install tmux",
+            "brew install nvmi",
+            "brew list --versions",
+        ]);
+        let state = context.state.borrow();
+        assert!(state.installed_by_dotter("brew", "tmux"));
+        assert!(state.installed_by_dotter("brew", "jq"));
+    }
+
+    #[test]
+    fn an_atomic_run_removes_what_a_failed_batch_installed() {
+        let (runner, mut context) = faked();
+        context.atomic = true;
+        context.state.borrow_mut().installed_package("brew", "git");
+        runner.print("brew list --versions", "git 2.42.0\n");
+        runner.print("brew list --versions", "git 2.42.0\njq 1.7\n");
+        runner.respond("brew install tmux nvmi jq", 1, "Error: No available formula with the name \"nvmi\".");
+        runner.respond("brew install nvmi", 1, "Error: No available formula with the name \"nvmi\".");
+        let group = brews(r#"["git", "tmux", "nvmi", "jq"]"#);
+
+        transaction::execute(group.steps(), &context).unwrap_err();
+
+        assert_eq!(runner.commands()[5..], ["brew remove jq", "brew remove tmux"]);
+        let state = context.state.borrow();
+        assert!(!state.installed_by_dotter("brew", "tmux"));
+        assert!(!state.installed_by_dotter("brew", "jq"));
+        assert!(state.installed_by_dotter("brew", "git"));
+    }
+
+    #[test]
+    fn explaining_a_group_of_brews_lists_them_once() {
+        let (runner, context) = faked();
+        runner.print("brew list --versions", "git 2.40.0\n");
+
+        let explanations = brews(r#"["git", {"tap": "neovim/neovim", "name": "nvim"}]"#).explain(&context).unwrap();
+
+        let messages: Vec<String> = explanations.into_iter().map(|explanation| explanation.message).collect();
+        assert_eq!(messages, vec!["git 2.40.0 is already installed, nothing to do", "Will install neovim/neovim/nvim"]);
+        assert_eq!(runner.commands(), vec!["brew list --versions"]);
+    }
+
+    fn taps(json: &str) -> Taps {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn missing_taps_are_added_and_recorded() {
+        let (runner, context) = faked();
+        runner.print("brew tap", "homebrew/core\nhomebrew/cask-fonts\n");
+
+        taps(r#"["Homebrew/cask-fonts", "neovim/neovim", {"name": "acme/private", "url": "https://acme.test/tap.git"}]"#)
+            .execute(&context)
+            .unwrap();
+
+        assert_eq!(runner.commands(), vec![
+            "brew tap",
+            "brew tap neovim/neovim",
+            "brew tap acme/private https://acme.test/tap.git",
+        ]);
+        let state = context.state.borrow();
+        assert!(state.installed_by_dotter("brew-tap", "acme/private"));
+        assert!(!state.installed_by_dotter("brew-tap", "Homebrew/cask-fonts"));
+    }
+
+    #[test]
+    fn only_taps_added_by_dotter_are_untapped() {
+        let (runner, context) = faked();
+        context.state.borrow_mut().installed_package("brew-tap", "neovim/neovim");
+        context.state.borrow_mut().found_package("brew-tap", "homebrew/cask-fonts");
+
+        taps(r#"["homebrew/cask-fonts", "neovim/neovim"]"#).rollback(&context).unwrap();
+
+        assert_eq!(runner.commands(), vec!["brew untap neovim/neovim"]);
+        assert!(!context.state.borrow().installed_by_dotter("brew-tap", "neovim/neovim"));
+    }
+
+    #[test]
+    fn explaining_taps_shows_which_are_missing() {
+        let (runner, context) = faked();
+        runner.print("brew tap", "homebrew/cask-fonts\n");
+
+        let explanations = taps(r#"["homebrew/cask-fonts", {"name": "acme/private", "url": "https://acme.test/tap.git"}]"#)
+            .explain(&context)
+            .unwrap();
+
+        let messages: Vec<String> = explanations.into_iter().map(|explanation| explanation.message).collect();
+        assert_eq!(messages, vec!["homebrew/cask-fonts is already tapped, nothing to do", "Will tap acme/private from https://acme.test/tap.git"]);
+    }
+
+    #[test]
+    fn tapped_brews_are_removed_by_their_full_name() {
+        let (runner, context) = faked();
+        context.state.borrow_mut().installed_package("brew", "neovim/neovim/nvim");
+
+        let brew = Brew::FromTap(TappedBrew::new("neovim/neovim", "nvim"));
+        brew.rollback(&context).unwrap();
+
+        assert_eq!(runner.commands(), vec!["brew remove neovim/neovim/nvim"]);
+    }
+
+    #[test]
+    fn explaining_shows_upgrades_and_pins() {
+        let (runner, context) = faked();
+        runner.print("brew list --versions", "nvim 0.8.0\npostgresql@14 14.8\ngit 2.40.0\njq 1.6\n");
+        runner.print("brew outdated --verbose", "nvim (0.8.0) < 0.9.1\npostgresql@14 (14.8) < 14.9 [pinned at 14.8]\n");
+        runner.print("brew list --pinned", "postgresql@14\n");
+
+        let explanations = brews(r#"[
+            {"name": "nvim", "upgrade": "outdated"},
+            {"name": "postgresql", "version": "14", "pin": true},
+            {"name": "git", "upgrade": "always"},
+            "jq",
+            {"name": "tmux", "pin": true}
+        ]"#).explain(&context).unwrap();
+
+        let messages: Vec<String> = explanations.into_iter().map(|explanation| explanation.message).collect();
+        assert_eq!(messages, vec![
+            "Will upgrade nvim 0.8.0 -> 0.9.1",
+            "postgresql@14 14.8 is pinned, skipping",
+            "git 2.40.0 is up to date, nothing to do",
+            "jq 1.6 is already installed, nothing to do",
+            "Will install tmux and pin it",
+        ]);
+    }
+
+    #[test]
+    fn brews_are_upgraded_and_pinned_as_asked() {
+        let (runner, context) = faked();
+        runner.print("brew list --versions", "nvim 0.8.0\ngit 2.40.0\njq 1.6\nripgrep 13.0.0\n");
+        runner.print("brew outdated --verbose", "nvim (0.8.0) < 0.9.1\njq (1.6) < 1.7\n");
+        runner.print("brew list --pinned", "ripgrep\n");
+
+        brews(r#"[
+            {"name": "nvim", "upgrade": "outdated"},
+            {"name": "git", "upgrade": "always"},
+            "jq",
+            {"name": "ripgrep", "pin": true},
+            {"name": "postgresql", "version": "14", "pin": true, "upgrade": "always"}
+        ]"#).execute(&context).unwrap();
+
+        assert_eq!(runner.commands(), vec![
+            "brew list --versions",
+            "brew outdated --verbose",
+            "brew list --pinned",
+            "brew upgrade nvim",
+            "brew upgrade git",
+            "brew install postgresql@14",
+            "brew pin postgresql@14",
+        ]);
+        assert!(context.state.borrow().installed_by_dotter("brew", "postgresql@14"));
+    }
+
+    #[test]
+    fn versioning_is_left_out_when_serializing_defaults() {
+        let brews = brews(r#"[{"name": "nvim"}, {"name": "postgresql", "version": "14", "pin": true, "upgrade": "never"}]"#);
+
+        assert_eq!(serde_json::to_string(&brews).unwrap(), r#"[{"name":"nvim"},{"name":"postgresql","version":"14","pin":true}]"#);
+    }
+
+    #[test]
+    fn brew_is_found_on_the_path() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::File::create(dir.path().join("brew")).unwrap();
+        let context = Context {
+            environment: hashmap! { "PATH".to_string() => format!("/nonexistent:{}", dir.path().display()) },
+            ..Context::default()
+        };
+
+        assert_eq!(locate(&context).unwrap(), Some(dir.path().join("brew")));
+    }
+
+    #[test]
+    fn the_brew_path_setting_wins() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::File::create(dir.path().join("brew")).unwrap();
+        let context = Context {
+            environment: hashmap! { "PREFIX".to_string() => dir.path().display().to_string() },
+            settings: Settings { brew_path: Some("$PREFIX/brew".to_string()), ..Settings::default() },
+            ..Context::default()
+        };
+
+        assert_eq!(locate(&context).unwrap(), Some(dir.path().join("brew")));
+    }
+
+    #[test]
+    fn a_brew_path_that_does_not_exist_is_an_error() {
+        let context = Context {
+            settings: Settings { brew_path: Some("/nonexistent/brew".to_string()), ..Settings::default() },
+            ..Context::default()
+        };
+
+        assert_eq!(locate(&context).unwrap_err().to_string(), "brew_path is set to /nonexistent/brew, which does not exist");
+    }
+
+    #[test]
+    fn the_located_brew_is_used_for_everything() {
+        let runner = Rc::new(FakeRunner::default());
+        let context = Context {
+            runner: runner.clone(),
+            brew: PathBuf::from("/opt/homebrew/bin/brew"),
+            ..Context::default()
+        };
+
+        brews(r#"["git"]"#).execute(&context).unwrap();
+
+        assert_eq!(runner.commands(), vec!["/opt/homebrew/bin/brew list --versions", "/opt/homebrew/bin/brew install git"]);
+    }
+
+    fn bootstrapping(installer: &[u8], sha256: Option<&str>) -> (tempfile::TempDir, Rc<FakeRunner>, Context) {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("install.sh"), installer).unwrap();
+        let runner = Rc::new(FakeRunner::default());
+        let context = Context {
+            runner: runner.clone(),
+            working_directory: dir.path().to_path_buf(),
+            settings: Settings {
+                homebrew_installer: Some("install.sh".to_string()),
+                homebrew_installer_sha256: sha256.map(String::from),
+                ..Settings::default()
+            },
+            ..Context::default()
+        };
+        (dir, runner, context)
+    }
+
+    #[test]
+    fn homebrew_is_installed_with_a_verified_installer() {
+        let checksum = sha256(b"echo installing");
+        let (_dir, runner, context) = bootstrapping(b"echo installing", Some(&checksum.to_uppercase()));
+
+        install_homebrew(&context).unwrap();
+
+        let invocations = runner.invocations();
+        assert_eq!(runner.commands(), vec!["/bin/bash -c echo installing"]);
+        assert_eq!(invocations[0].environment.as_ref().unwrap()["NONINTERACTIVE"], "1");
+    }
+
+    #[test]
+    fn an_installer_with_the_wrong_checksum_is_not_run() {
+        let (_dir, runner, context) = bootstrapping(b"echo tampered", Some("0123"));
+
+        let error = install_homebrew(&context).unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            format!("Could not install Homebrew: install.sh has the sha256 {}, expected 0123", sha256(b"echo tampered"))
+        );
+        assert!(runner.commands().is_empty());
+    }
+
+    #[test]
+    fn homebrew_is_not_installed_without_a_checksum() {
+        let (_dir, runner, context) = bootstrapping(b"echo installing", None);
+
+        assert!(install_homebrew(&context).is_err());
+        assert!(runner.commands().is_empty());
+        assert_eq!(
+            explain_install_homebrew(&context).message,
+            "Homebrew is missing and can not be installed without homebrew_installer_sha256 in the settings"
+        );
+    }
+
+    #[test]
+    fn explaining_works_before_homebrew_is_installed() {
+        let context = Context {
+            brew: PathBuf::from("/nonexistent/brew"),
+            settings: Settings { homebrew_installer_sha256: Some("abc".to_string()), ..Settings::default() },
+            ..Context::default()
+        };
+
+        let explanations = brews(r#"["git"]"#).explain(&context).unwrap();
+
+        assert_eq!(explanations[0].message, "Will install git");
+        assert_eq!(
+            explain_install_homebrew(&context).message,
+            format!("Homebrew is missing, will check and run the installer from {}", INSTALLER)
+        );
+    }
+
+    #[test]
+    fn brews_can_have_a_timeout_and_retries() {
+        let brews: Vec<Brew> = serde_json::from_str(r#"[
+            "git",
+            {"name": "nvim", "timeout_secs": 600, "retries": 2, "retry_delay_secs": 30},
+            {"cask": "intellij-idea", "timeout_secs": 60}
+        ]"#).unwrap();
+
+        assert_eq!(brews[0].policy(), RetryPolicy::default());
+        assert_eq!(brews[1].name(), "nvim");
+        assert_eq!(brews[1].policy(), RetryPolicy { timeout_secs: Some(600), retries: 2, retry_delay_secs: 30 });
+        assert_eq!(brews[2].policy().timeout_secs, Some(60));
+    }
+
+    fn status(brew: &Brew) -> BrewStatus {
+        let brews = std::slice::from_ref(brew);
+        Installed::query(brews, false, &Context::default()).unwrap().status(brew)
+    }
 
     fn assert_installed(brew: &Brew) {
-        assert_eq!( brew.status(), BrewStatus::Installed);
+        assert_ne!(status(brew), BrewStatus::Missing);
     }
 
     fn assert_missing(brew: &Brew) {
-        assert_eq!( brew.status(), BrewStatus::Missing);
+        assert_eq!(status(brew), BrewStatus::Missing);
     }
 }
@@ -1,60 +1,539 @@
-use std::collections::HashMap;
-use std::fs::File;
-use std::path::Path;
+use serde::de::{self, Deserialize, Deserializer, MapAccess, Visitor};
+use std::env;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::result;
 
 use crate::group::Group;
-use crate::{Command, Context, Explanation, Result};
+use crate::settings::Settings;
+use crate::{Command, Context, Direction, Explanation, Result};
 
-#[derive(Deserialize, Debug)]
-pub struct Inventory(HashMap<String, Group>);
+/// The groups of an inventory, kept in the order they were declared in, and its settings.
+#[derive(Debug)]
+pub struct Inventory {
+    groups: Vec<(String, Group)>,
+    settings: Settings,
+}
 
 impl Inventory {
     pub fn group<S: Into<String>>(&mut self, group: S) -> Option<Group> {
-        self.0.remove(&group.into())
+        let name = group.into();
+        let position = self.groups.iter().position(|(key, _)| *key == name)?;
+        Some(self.groups.remove(position).1)
+    }
+
+    /// Narrows the inventory down to what should be applied, ordered so that every group comes after the groups it requires.
+    ///
+    /// With `only`, just that group is kept, plus everything it transitively requires if `with_requirements` is set.
+    pub fn select(mut self, only: Option<&str>, with_requirements: bool) -> Result<Inventory> {
+        let ordered = self.resolve()?;
+
+        let selected: Vec<String> = match only {
+            None => ordered,
+            Some(name) => {
+                if self.find(name).is_none() {
+                    return Err(DependencyError::UnknownGroup(name.to_string()).into());
+                }
+
+                let mut wanted = vec![name.to_string()];
+                let mut index = 0;
+                while with_requirements && index < wanted.len() {
+                    for requirement in self.find(&wanted[index]).unwrap().requires() {
+                        if !wanted.contains(requirement) {
+                            wanted.push(requirement.clone());
+                        }
+                    }
+                    index += 1;
+                }
+
+                ordered.into_iter().filter(|name| wanted.contains(name)).collect()
+            }
+        };
+
+        let groups = selected
+            .into_iter()
+            .map(|name| {
+                let group = self.group(name.as_str()).unwrap();
+                (name, group)
+            })
+            .collect();
+
+        Ok(Inventory {
+            groups,
+            settings: self.settings,
+        })
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    pub fn needs_homebrew(&self, context: &Context) -> bool {
+        self.groups().any(|group| group.needs_homebrew(context))
+    }
+
+    pub fn groups(&self) -> impl Iterator<Item = &Group> {
+        self.groups.iter().map(|(_, group)| group)
+    }
+
+    fn find(&self, name: &str) -> Option<&Group> {
+        self.groups.iter().find(|(key, _)| key == name).map(|(_, group)| group)
+    }
+
+    /// Orders all groups so that requirements come first, otherwise keeping the declaration order.
+    fn resolve(&self) -> Result<Vec<String>> {
+        let mut ordered = Vec::new();
+        for (name, _) in self.groups.iter() {
+            self.visit(name, &mut Vec::new(), &mut ordered)?;
+        }
+        Ok(ordered)
+    }
+
+    fn visit(&self, name: &str, path: &mut Vec<String>, ordered: &mut Vec<String>) -> Result<()> {
+        if ordered.iter().any(|done| done == name) {
+            return Ok(());
+        }
+
+        if let Some(start) = path.iter().position(|seen| seen == name) {
+            let mut cycle = path[start..].to_vec();
+            cycle.push(name.to_string());
+            return Err(DependencyError::Cycle(cycle.join(" -> ")).into());
+        }
+
+        let group = self.find(name).unwrap();
+        path.push(name.to_string());
+        for requirement in group.requires() {
+            if self.find(requirement).is_none() {
+                return Err(DependencyError::UnknownRequirement(name.to_string(), requirement.clone()).into());
+            }
+            self.visit(requirement, path, ordered)?;
+        }
+        path.pop();
+
+        ordered.push(name.to_string());
+        Ok(())
+    }
+}
+
+#[derive(Fail, Debug)]
+pub enum DependencyError {
+    #[fail(display = "Group '{}' does not exist", _0)]
+    UnknownGroup(String),
+    #[fail(display = "Group '{}' requires '{}', which does not exist", _0, _1)]
+    UnknownRequirement(String, String),
+    #[fail(display = "Groups require each other in a cycle: {}", _0)]
+    Cycle(String),
+}
+
+/// The key holding the `Settings`, so it can not be used as a group name.
+const SETTINGS: &str = "settings";
+
+impl<'de> Deserialize<'de> for Inventory {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> result::Result<Self, D::Error> {
+        struct InventoryVisitor;
+
+        impl<'de> Visitor<'de> for InventoryVisitor {
+            type Value = Inventory;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a map of group names to groups")
+            }
+
+            fn visit_map<M: MapAccess<'de>>(self, mut map: M) -> result::Result<Inventory, M::Error> {
+                let mut groups: Vec<(String, Group)> = Vec::new();
+                let mut settings = None;
+                while let Some(name) = map.next_key::<String>()? {
+                    if name == SETTINGS {
+                        if settings.is_some() {
+                            return Err(de::Error::duplicate_field(SETTINGS));
+                        }
+                        settings = Some(map.next_value::<Settings>()?);
+                        continue;
+                    }
+
+                    let mut group: Group = map.next_value()?;
+                    if groups.iter().any(|(key, _)| *key == name) {
+                        return Err(de::Error::custom(format!("group '{}' is declared twice", name)));
+                    }
+                    group.set_name(&name);
+                    groups.push((name, group));
+                }
+                Ok(Inventory {
+                    groups,
+                    settings: settings.unwrap_or_default(),
+                })
+            }
+        }
+
+        deserializer.deserialize_map(InventoryVisitor)
     }
 }
 
 impl Command for Inventory {
     fn execute(&self, context: &Context) -> Result<()> {
-        for (_key, value) in self.0.iter() {
-            value.execute(&context)?;
+        for (_key, value) in self.groups.iter() {
+            value.execute(context)?;
         }
         Ok(())
     }
 
     fn rollback(&self, context: &Context) -> Result<()> {
-        for (_key, value) in self.0.iter() {
-            value.rollback(&context)?;
+        for (_key, value) in self.groups.iter().rev() {
+            value.rollback(context)?;
         }
         Ok(())
     }
 
     fn explain(&self, context: &Context) -> Result<Vec<Explanation>> {
         let mut explanations = Vec::new();
-        for (_key, value) in self.0.iter() {
-            explanations.append(&mut value.explain(&context)?);
+        for (_key, value) in context.direction.order(&self.groups) {
+            explanations.append(&mut value.explain(context)?);
         }
         Ok(explanations)
     }
+
+    fn steps(&self) -> Vec<&dyn Command> {
+        self.groups.iter().flat_map(|(_key, value)| value.steps()).collect()
+    }
 }
 
-pub fn read_inventory<P: AsRef<Path>>(path: P) -> Result<Inventory> {
-    let file = File::open(path)?;
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Format {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl Format {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Format> {
+        match path.as_ref().extension().and_then(OsStr::to_str) {
+            Some("json") => Some(Format::Json),
+            Some("toml") => Some(Format::Toml),
+            Some("yml") | Some("yaml") => Some(Format::Yaml),
+            _ => None,
+        }
+    }
+}
+
+impl std::str::FromStr for Format {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(Format::Json),
+            "toml" => Ok(Format::Toml),
+            "yml" | "yaml" => Ok(Format::Yaml),
+            other => Err(format_err!("Unknown inventory format '{}', expected 'json', 'toml' or 'yaml'", other)),
+        }
+    }
+}
+
+#[derive(Fail, Debug)]
+#[fail(display = "Could not parse {} at line {}, column {}: {}", file, line, column, message)]
+pub struct ParseError {
+    file: String,
+    line: usize,
+    column: usize,
+    message: String,
+}
+
+impl ParseError {
+    fn new(file: &str, line: usize, column: usize, error: &dyn std::fmt::Display) -> ParseError {
+        // serde_json, toml and serde_yaml all append the location to their messages, we report it ourselves.
+        let message = error.to_string();
+        let suffix = format!(" at line {} column {}", line, column);
+        let message = message.trim_end_matches(suffix.as_str()).to_string();
+
+        ParseError {
+            file: file.to_string(),
+            line,
+            column,
+            message,
+        }
+    }
+}
+
+/// Whether `--only` also pulls in the groups the named one requires. A run needs them in place, but
+/// rolling back one group leaves the groups it builds on alone, as other groups may need them too.
+pub fn with_requirements(direction: &Direction, no_deps: bool) -> bool {
+    match direction {
+        Direction::Execute => !no_deps,
+        Direction::Rollback => false,
+    }
+}
+
+/// Reads an inventory from `path`, or from stdin if `path` is `-`.
+///
+/// Without an explicit `format` it is picked from the file extension, falling back to JSON.
+pub fn read_inventory<P: AsRef<Path>>(path: P, format: Option<Format>) -> Result<Inventory> {
+    let path = path.as_ref();
+    let format = format.or_else(|| Format::from_path(path)).unwrap_or(Format::Json);
 
-    let i = serde_json::from_reader(file)?;
+    let mut content = String::new();
+    let name = if path == Path::new("-") {
+        io::stdin().read_to_string(&mut content)?;
+        "<stdin>".to_string()
+    } else {
+        File::open(path)?.read_to_string(&mut content)?;
+        path.display().to_string()
+    };
 
-    Ok(i)
+    parse_inventory(&name, &content, format)
+}
+
+/// The directory relative paths in the inventory are resolved against: the one the inventory
+/// file is in, or the current directory when it is read from stdin.
+pub fn working_directory<P: AsRef<Path>>(path: P) -> Result<PathBuf> {
+    match location(path)? {
+        Some(inventory) => Ok(inventory.parent().map(Path::to_path_buf).unwrap_or(inventory)),
+        None => Ok(env::current_dir()?),
+    }
+}
+
+/// The canonical path of the inventory file, or `None` when it is read from stdin.
+pub fn location<P: AsRef<Path>>(path: P) -> Result<Option<PathBuf>> {
+    let path = path.as_ref();
+    if path == Path::new("-") {
+        return Ok(None);
+    }
+
+    let inventory = fs::canonicalize(path).map_err(|e| format_err!("Could not find {}: {}", path.display(), e))?;
+    Ok(Some(inventory))
+}
+
+fn parse_inventory(name: &str, content: &str, format: Format) -> Result<Inventory> {
+    let inventory = match format {
+        Format::Json => serde_json::from_str(content)
+            .map_err(|e| ParseError::new(name, e.line(), e.column(), &e))?,
+        Format::Toml => toml::from_str(content).map_err(|e| {
+            let (line, column) = e.line_col().map(|(l, c)| (l + 1, c + 1)).unwrap_or((0, 0));
+            ParseError::new(name, line, column, &e)
+        })?,
+        Format::Yaml => serde_yaml::from_str(content).map_err(|e| {
+            let (line, column) = e.location().map(|l| (l.line(), l.column())).unwrap_or((0, 0));
+            ParseError::new(name, line, column, &e)
+        })?,
+    };
+
+    Ok(inventory)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::packages::ProviderKind;
 
     #[test]
     fn it_can_read_the_inventory() {
-        let mut inventory: Inventory = read_inventory("samples/inventory.json").unwrap();
+        let mut inventory: Inventory = read_inventory("samples/inventory.json", None).unwrap();
 
         assert!(inventory.group("vim").is_some());
         assert!(inventory.group("homebrew").is_some());
     }
+
+    #[test]
+    fn relative_paths_are_resolved_next_to_the_inventory() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("dotfiles")).unwrap();
+        fs::write(dir.path().join("dotfiles/setup.sh"), "touch ran").unwrap();
+        let path = dir.path().join("dotfiles/inventory.json");
+        fs::write(&path, r#"{"setup": {"shell": [{"run": "sh ./setup.sh"}]}}"#).unwrap();
+
+        let inventory = read_inventory(&path, None).unwrap();
+        let context = Context {
+            working_directory: working_directory(&path).unwrap(),
+            ..Context::default()
+        };
+        inventory.execute(&context).unwrap();
+
+        assert_ne!(env::current_dir().unwrap(), context.working_directory);
+        assert!(dir.path().join("dotfiles/ran").exists());
+    }
+
+    #[test]
+    fn it_can_read_a_toml_inventory() {
+        let mut inventory: Inventory = read_inventory("samples/inventory.toml", None).unwrap();
+
+        assert!(inventory.group("vim").is_some());
+        assert!(inventory.group("homebrew").is_some());
+    }
+
+    #[test]
+    fn it_can_read_a_yaml_inventory() {
+        let mut inventory: Inventory = read_inventory("samples/inventory.yml", None).unwrap();
+
+        assert!(inventory.group("vim").is_some());
+        assert!(inventory.group("homebrew").is_some());
+        assert!(inventory.group("java").is_some());
+    }
+
+    #[test]
+    fn yaml_anchors_can_be_reused_between_groups() {
+        let yaml = "
+base:
+  brew: &common
+    - git
+    - htop
+work:
+  brew: *common
+";
+        let mut inventory = parse_inventory("anchors.yml", yaml, Format::Yaml).unwrap();
+
+        let base = format!("{:?}", inventory.group("base").unwrap());
+        let work = format!("{:?}", inventory.group("work").unwrap());
+        assert_eq!(base, work);
+    }
+
+    #[test]
+    fn groups_keep_the_order_they_were_declared_in() {
+        for sample in &["samples/inventory.json", "samples/inventory.toml", "samples/inventory.yml"] {
+            let inventory = read_inventory(sample, None).unwrap();
+
+            assert_eq!(names(&inventory)[..2], ["vim", "homebrew"], "wrong order for {}", sample);
+        }
+    }
+
+    #[test]
+    fn groups_can_not_be_declared_twice() {
+        let error = parse_inventory("twice.yml", "vim: {}\nvim: {}\n", Format::Yaml);
+
+        assert!(error.is_err());
+    }
+
+    fn names(inventory: &Inventory) -> Vec<&str> {
+        inventory.groups.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    const DEPENDENCIES: &str = "
+vim:
+  requires: [homebrew]
+java: {}
+homebrew:
+  requires: [base]
+base: {}
+";
+
+    #[test]
+    fn requirements_are_applied_first() {
+        let inventory = parse_inventory("deps.yml", DEPENDENCIES, Format::Yaml).unwrap();
+
+        let selected = inventory.select(None, true).unwrap();
+
+        assert_eq!(names(&selected), ["base", "homebrew", "vim", "java"]);
+    }
+
+    #[test]
+    fn only_pulls_in_transitive_requirements() {
+        let inventory = parse_inventory("deps.yml", DEPENDENCIES, Format::Yaml).unwrap();
+
+        let selected = inventory.select(Some("vim"), true).unwrap();
+
+        assert_eq!(names(&selected), ["base", "homebrew", "vim"]);
+    }
+
+    #[test]
+    fn requirements_can_be_left_out() {
+        let inventory = parse_inventory("deps.yml", DEPENDENCIES, Format::Yaml).unwrap();
+
+        let selected = inventory.select(Some("vim"), false).unwrap();
+
+        assert_eq!(names(&selected), ["vim"]);
+    }
+
+    #[test]
+    fn rolling_back_one_group_leaves_its_requirements() {
+        let inventory = parse_inventory("deps.yml", DEPENDENCIES, Format::Yaml).unwrap();
+
+        let selected = inventory.select(Some("vim"), with_requirements(&Direction::Rollback, false)).unwrap();
+
+        assert_eq!(names(&selected), ["vim"]);
+        assert!(with_requirements(&Direction::Execute, false));
+        assert!(!with_requirements(&Direction::Execute, true));
+    }
+
+    #[test]
+    fn cycles_are_reported() {
+        let yaml = "a: {requires: [b]}\nb: {requires: [c]}\nc: {requires: [a]}\n";
+        let inventory = parse_inventory("cycle.yml", yaml, Format::Yaml).unwrap();
+
+        let error = inventory.select(None, true).unwrap_err();
+
+        assert_eq!(error.to_string(), "Groups require each other in a cycle: a -> b -> c -> a");
+    }
+
+    #[test]
+    fn unknown_requirements_are_reported() {
+        let inventory = parse_inventory("unknown.yml", "vim: {requires: [nope]}", Format::Yaml).unwrap();
+
+        let error = inventory.select(None, true).unwrap_err();
+
+        assert_eq!(error.to_string(), "Group 'vim' requires 'nope', which does not exist");
+    }
+
+    #[test]
+    fn the_format_can_be_overridden() {
+        let result = read_inventory("samples/inventory.toml", Some(Format::Json));
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn parse_errors_point_at_the_problem() {
+        let error = parse_inventory("broken.json", "{\n  \"vim\": [\n}", Format::Json).unwrap_err();
+
+        assert_eq!(error.to_string(), "Could not parse broken.json at line 3, column 1: expected value");
+
+        let error = parse_inventory("broken.toml", "[vim]\nbrew = [\n", Format::Toml).unwrap_err();
+
+        assert!(error.to_string().starts_with("Could not parse broken.toml at line 3, column 1:"));
+
+        let error = parse_inventory("broken.yml", "vim:\n  brew: [git\n", Format::Yaml).unwrap_err();
+
+        assert!(error.to_string().starts_with("Could not parse broken.yml at line 3, column 1:"));
+    }
+
+    #[test]
+    fn settings_are_read_next_to_the_groups() {
+        let inventory = parse_inventory("inventory.yml", "settings:\n  brew_path: /opt/homebrew/bin/brew\nvim:\n  brew: [nvim]\n", Format::Yaml).unwrap();
+
+        assert_eq!(inventory.settings().brew_path, Some("/opt/homebrew/bin/brew".to_string()));
+        assert_eq!(names(&inventory), ["vim"]);
+    }
+
+    #[test]
+    fn unknown_settings_are_rejected() {
+        let error = parse_inventory("inventory.json", r#"{"settings": {"brew": "/usr/bin/brew"}}"#, Format::Json).unwrap_err();
+
+        assert!(error.to_string().contains("unknown field `brew`"), "{}", error);
+    }
+
+    #[test]
+    fn homebrew_is_only_needed_for_groups_with_brews_or_taps() {
+        let content = "fonts:\n  taps: [homebrew/cask-fonts]\nvim:\n  brew: [nvim]\nbase:\n  shell: [{run: 'true'}]\n";
+        let inventory = |only| parse_inventory("inventory.yml", content, Format::Yaml).unwrap().select(Some(only), true).unwrap();
+
+        let context = Context::default();
+
+        assert!(inventory("fonts").needs_homebrew(&context));
+        assert!(inventory("vim").needs_homebrew(&context));
+        assert!(!inventory("base").needs_homebrew(&context));
+    }
+
+    #[test]
+    fn homebrew_is_needed_for_packages_that_default_to_it() {
+        let content = "tools:
+  packages: [ripgrep]
+";
+        let inventory = parse_inventory("inventory.yml", content, Format::Yaml).unwrap();
+        let with_provider = |provider| Context {
+            settings: Settings { package_provider: Some(provider), ..Settings::default() },
+            ..Context::default()
+        };
+
+        assert!(inventory.needs_homebrew(&with_provider(ProviderKind::Brew)));
+        assert!(!inventory.needs_homebrew(&with_provider(ProviderKind::Apt)));
+    }
 }
@@ -1,3 +1,6 @@
+// failure_derive expands into impls nested inside constants, which newer compilers flag.
+#![allow(non_local_definitions)]
+
 extern crate serde;
 extern crate serde_json;
 extern crate symlink;
@@ -7,18 +10,33 @@ extern crate clap;
 #[macro_use] extern crate failure;
 #[macro_use] extern crate serde_derive;
 
+mod brewfile;
 mod homebrew;
 mod inventory;
+mod packages;
+mod process;
 mod shell;
 mod symlinks;
 mod group;
-
-use crate::homebrew::{is_homebrew_installed, install_homebrew};
+mod prune;
+mod script;
+mod settings;
+mod state;
+mod toolchains;
+mod transaction;
+
+use crate::homebrew::{explain_install_homebrew, install_homebrew};
+use crate::process::{Runner, SystemRunner};
+use crate::prune::Prune;
+use crate::settings::Settings;
+use crate::state::{Resource, State};
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::default::Default;
-use std::{env, result};
+use std::rc::Rc;
+use std::{env, fs, result};
 use std::path::PathBuf;
-use clap::{App, Arg, ArgMatches, ArgGroup};
+use clap::{App, AppSettings, Arg, ArgMatches, ArgGroup, SubCommand};
 use std::str::FromStr;
 
 pub type Result<T> = result::Result<T, failure::Error>;
@@ -29,27 +47,85 @@ fn main() -> Result<()> {
         .about("Think of a minimal subset of anisble, without any dependencies")
         .group(
             ArgGroup::with_name("execution")
-            .args(&["direction", "inventory", "explain", "only"])
+            .args(&["direction", "inventory", "explain", "only", "no-deps", "format", "atomic", "state"])
             .multiple(true)
             .requires_all(&["direction", "inventory"])
             .required(false))
         .arg(Arg::with_name("direction")
              .takes_value(true)
              .index(1)
-             .possible_values(&["run", "rollback"]))
+             .possible_values(&["run", "rollback", "prune"]))
         .arg(Arg::with_name("inventory")
              .index(2)
              .takes_value(true))
         .arg(Arg::with_name("explain").short("e").long("explain").requires("execution"))
+        .arg(
+            Arg::with_name("atomic")
+            .long("atomic")
+            .help("Roll back the steps that already succeeded when a later one fails")
+            .requires("execution"))
         .arg(
             Arg::with_name("only")
             .short("o")
             .long("only")
             .takes_value(true)
             .requires("execution"))
+        .arg(
+            Arg::with_name("no-deps")
+            .long("no-deps")
+            .help("Do not pull in the groups required by the one named with --only")
+            .requires("only"))
+        .arg(
+            Arg::with_name("format")
+            .short("f")
+            .long("format")
+            .takes_value(true)
+            .possible_values(&["json", "toml", "yaml", "yml"])
+            .help("Format of the inventory, guessed from the file extension if omitted. Use '-' as the inventory to read stdin.")
+            .requires("execution"))
+        .arg(
+            Arg::with_name("state")
+            .long("state")
+            .takes_value(true)
+            .help("Where to keep track of what dotter changed, defaults to ~/.local/state/dotter/state.json")
+            .requires("execution"))
         .arg(
             Arg::with_name("version").short("v").long("version").conflicts_with("execution")
             )
+        .subcommand(
+            SubCommand::with_name("import")
+            .about("Converts other formats into inventory groups")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(
+                SubCommand::with_name("brewfile")
+                .about("Prints the tap, brew and cask lines of a Brewfile as an inventory group")
+                .arg(Arg::with_name("brewfile").index(1).required(true))
+                .arg(
+                    Arg::with_name("group")
+                    .short("g")
+                    .long("group")
+                    .takes_value(true)
+                    .default_value("homebrew"))))
+        .subcommand(
+            SubCommand::with_name("export")
+            .about("Converts inventory groups into other formats")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(
+                SubCommand::with_name("brewfile")
+                .about("Prints the brews of an inventory as a Brewfile")
+                .arg(Arg::with_name("inventory").index(1).required(true))
+                .arg(Arg::with_name("only").short("o").long("only").takes_value(true))
+                .arg(
+                    Arg::with_name("no-deps")
+                    .long("no-deps")
+                    .help("Do not pull in the groups required by the one named with --only")
+                    .requires("only"))
+                .arg(
+                    Arg::with_name("format")
+                    .short("f")
+                    .long("format")
+                    .takes_value(true)
+                    .possible_values(&["json", "toml", "yaml", "yml"]))))
         .get_matches();
 
     if matches.is_present("version") {
@@ -57,44 +133,163 @@ fn main() -> Result<()> {
         return Ok(());
     }
 
-
-    if !is_homebrew_installed() {
-        install_homebrew();
+    match matches.subcommand() {
+        ("import", Some(import)) => return import_brewfile(import.subcommand_matches("brewfile").unwrap()),
+        ("export", Some(export)) => return export_brewfile(export.subcommand_matches("brewfile").unwrap()),
+        _ => (),
     }
 
-    let mut inv = inventory::read_inventory(&matches.value_of("inventory").unwrap())?;
+    let inventory_path = matches.value_of("inventory").unwrap();
+    let inv = inventory::read_inventory(inventory_path, format(&matches)?)?;
+    let working_directory = inventory::working_directory(inventory_path)?;
+    let inventory_file = inventory::location(inventory_path)?;
 
-    let target: Box<dyn Command> = if let Some(name) = &matches.value_of("only") {
-        let group = inv.group(name.as_ref()).expect("did not find group.");
-        Box::new(group)
-    } else {
-        Box::new(inv)
-    };
+    let pruning = matches.value_of("direction") == Some("prune");
+    if pruning && matches.is_present("only") {
+        return Err(format_err!("prune needs the whole inventory and can not be combined with --only"));
+    }
+    if pruning && inventory_file.is_none() {
+        return Err(format_err!("prune needs the inventory as a file, to tell what it applied from what other inventories did"));
+    }
+    let only = matches.value_of("only").map(String::from);
+    let no_deps = matches.is_present("no-deps");
+
+    let state_file = matches.value_of("state").map(PathBuf::from);
+    let mut context = Context::from(matches);
+    context.working_directory = working_directory;
+    let target = inv.select(only.as_deref(), inventory::with_requirements(&context.direction, no_deps))?;
+    let state_file = state_file
+        .or_else(|| State::default_location(&context.environment))
+        .ok_or_else(|| format_err!("Could not figure out where to keep the state, use --state"))?;
+    let state = State::load(&state_file)?;
+    context.state = RefCell::new(match inventory_file {
+        Some(inventory) => state.applied_by(inventory),
+        None => state,
+    });
+    context.settings = target.settings().clone();
+
+    match homebrew::locate(&context)? {
+        Some(brew) => context.brew = brew,
+        None if pruning || !target.needs_homebrew(&context) => (),
+        None => match context.direction {
+            Direction::Execute if context.explain => println!("{}", explain_install_homebrew(&context).message),
+            Direction::Execute => {
+                install_homebrew(&context)?;
+                context.brew = homebrew::locate(&context)?
+                    .ok_or_else(|| format_err!("Installed Homebrew, but could not find brew afterwards"))?;
+            }
+            Direction::Rollback => (),
+        },
+    }
 
-    let context = Context::from(matches);
-    if context.explain {
+    if pruning {
+        let prune = Prune::plan(&target, &context);
+        if context.explain {
+            for explanation in prune.explain() {
+                println!("{}", explanation.message);
+            }
+            Ok(())
+        } else {
+            let outcome = prune.execute(&context);
+            let saved = context.state.borrow().save(&state_file);
+            outcome.and(saved)
+        }
+    } else if context.explain {
         for explanation in target.explain(&context)? {
             println!("{}", explanation.message);
         }
         Ok(())
     } else {
-        target.dispatch(&context)
+        let outcome = target.dispatch(&context);
+        let saved = context.state.borrow().save(&state_file);
+        outcome.and(saved)
+    }
+}
+
+fn format(options: &ArgMatches) -> Result<Option<inventory::Format>> {
+    match options.value_of("format") {
+        Some(format) => Ok(Some(format.parse()?)),
+        None => Ok(None),
     }
 }
 
+fn import_brewfile(options: &ArgMatches) -> Result<()> {
+    let path = options.value_of("brewfile").unwrap();
+    let content = fs::read_to_string(path).map_err(|e| format_err!("Could not read {}: {}", path, e))?;
+    let imported = brewfile::parse(&content)?;
+    for warning in &imported.warnings {
+        eprintln!("{}", warning);
+    }
+
+    let mut group = serde_json::Map::new();
+    if !imported.taps.is_empty() {
+        group.insert("taps".to_string(), serde_json::to_value(&imported.taps)?);
+    }
+    group.insert("brew".to_string(), serde_json::to_value(&imported.brews)?);
+
+    let mut inventory = serde_json::Map::new();
+    inventory.insert(options.value_of("group").unwrap().to_string(), group.into());
+    println!("{}", serde_json::to_string_pretty(&inventory)?);
+    Ok(())
+}
+
+fn export_brewfile(options: &ArgMatches) -> Result<()> {
+    let inventory = inventory::read_inventory(options.value_of("inventory").unwrap(), format(options)?)?
+        .select(options.value_of("only"), !options.is_present("no-deps"))?;
+
+    let taps = inventory.groups().flat_map(|group| group.taps().iter());
+    let brews = inventory.groups().flat_map(|group| group.brews().iter());
+    print!("{}", brewfile::render(taps, brews));
+    Ok(())
+}
+
 pub struct Context {
     direction: Direction,
     environment: HashMap<String, String>,
     explain: bool,
+    atomic: bool,
+    state: RefCell<State>,
+    runner: Rc<dyn Runner>,
+    settings: Settings,
+    /// The brew binary, just `brew` until `homebrew::locate` found where it is.
+    brew: PathBuf,
     working_directory: PathBuf
 }
 
+impl Context {
+    /// Expands `$VARIABLES` from the environment in `target` and resolves it against the working directory.
+    pub fn interpolate(&self, target: &str) -> PathBuf {
+        self.working_directory.join(self.expand(target))
+    }
+
+    /// Expands `$VARIABLES` from the environment in `target`.
+    pub fn expand(&self, target: &str) -> String {
+        if !target.contains('$') {
+            return target.to_string()
+        }
+
+        let mut better_target = target.to_string();
+        for (key, value) in self.environment.iter() {
+            if  target.contains(key) {
+                let x = format!("${}", key);
+                better_target = better_target.replace(&x, value);
+            }
+        }
+        better_target
+    }
+}
+
 impl Default for Context {
     fn default() -> Context {
         Context {
             direction: Direction::Execute,
             environment: env::vars().collect(),
             explain: false,
+            atomic: false,
+            state: RefCell::new(State::default()),
+            runner: Rc::new(SystemRunner),
+            settings: Settings::default(),
+            brew: PathBuf::from("brew"),
             working_directory: env::current_dir().expect("Could not get current directory")
         }
     }
@@ -106,8 +301,9 @@ impl <'a> std::convert::From<ArgMatches<'a>> for Context {
         let direction = options.value_of("direction").and_then(|dir| Direction::from_str(dir).ok()).unwrap_or(Direction::Execute);
 
         Context {
-            direction: direction,
+            direction,
             explain: options.is_present("explain"),
+            atomic: options.is_present("atomic"),
             ..Context::default()
         }
     }
@@ -130,6 +326,25 @@ pub enum Direction {
     Rollback
 }
 
+impl Direction {
+    /// Walks `items` in the order they are applied in: as declared when executing, reversed when rolling back.
+    fn order<'a, T>(&self, items: &'a [T]) -> Box<dyn Iterator<Item = &'a T> + 'a> {
+        match self {
+            Direction::Execute => Box::new(items.iter()),
+            Direction::Rollback => Box::new(items.iter().rev()),
+        }
+    }
+}
+
+impl std::fmt::Display for Direction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Direction::Execute => write!(f, "run"),
+            Direction::Rollback => write!(f, "rollback"),
+        }
+    }
+}
+
 impl std::str::FromStr for Direction {
     type Err = failure::Error;
 
@@ -145,6 +360,7 @@ impl std::str::FromStr for Direction {
 }
 
 
+#[derive(Debug)]
 pub struct Explanation {
     message: String
 }
@@ -160,8 +376,9 @@ impl Explanation {
 pub trait Command {
     fn dispatch(&self, context: &Context) -> Result<()> {
         match context.direction {
-            Direction::Execute => self.execute(&context),
-            Direction::Rollback => self.rollback(&context),
+            Direction::Execute if context.atomic => transaction::execute(self.steps(), context),
+            Direction::Execute => self.execute(context),
+            Direction::Rollback => self.rollback(context),
         }
     }
 
@@ -170,6 +387,19 @@ pub trait Command {
     fn rollback(&self, context: &Context) -> Result<()>;
 
     fn explain(&self, context: &Context) -> Result<Vec<Explanation>>;
+
+    /// The individual steps this command is made of, in execution order.
+    fn steps(&self) -> Vec<&dyn Command>;
+
+    /// What a single step puts onto the machine, so `prune` can tell what is still wanted.
+    fn resources(&self, _context: &Context) -> Vec<Resource> {
+        Vec::new()
+    }
+
+    /// Whether the last execute of a single step left it out, so there is nothing to roll back.
+    fn skipped(&self) -> bool {
+        false
+    }
 }
 
 impl<T: Command> Command for Vec<T> {
@@ -181,7 +411,7 @@ impl<T: Command> Command for Vec<T> {
     }
 
     fn rollback(&self, context: &Context) -> Result<()> {
-        for command in self {
+        for command in self.iter().rev() {
             command.rollback(context)?;
         }
         Ok(())
@@ -190,10 +420,14 @@ impl<T: Command> Command for Vec<T> {
     fn explain(&self, context: &Context) -> Result<Vec<Explanation>> {
         let mut explanations = Vec::new();
 
-        for command in self {
+        for command in context.direction.order(self) {
             explanations.append(&mut command.explain(context)?);
         }
 
         Ok(explanations)
     }
+
+    fn steps(&self) -> Vec<&dyn Command> {
+        self.iter().flat_map(|command| command.steps()).collect()
+    }
 }
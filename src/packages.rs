@@ -0,0 +1,568 @@
+use crate::homebrew::{self, Homebrew};
+use crate::process::{which, Finished, Invocation, Output};
+use crate::state::Resource;
+use crate::toolchains::{Cargo, Go, Npm, Pipx};
+use crate::transaction;
+use crate::{Command, Context, Direction, Explanation, Result};
+use serde::{Deserialize, Deserializer};
+use std::collections::HashSet;
+use std::io;
+use std::process::ExitStatus;
+
+/// A package manager that `packages` steps can install with.
+pub trait Provider {
+    /// How the provider is written in the inventory, and its packages named in the state journal.
+    fn name(&self) -> &'static str;
+
+    /// The names of every package that is installed.
+    fn installed(&self, context: &Context) -> Result<HashSet<String>>;
+
+    /// What `name` is called among the installed packages, when that is not simply its name.
+    fn installed_as<'a>(&self, name: &'a str) -> &'a str {
+        name
+    }
+
+    /// Installs all of `names` with one invocation.
+    fn install(&self, names: &[String], context: &Context) -> Result<()>;
+
+    fn remove(&self, name: &str, context: &Context) -> Result<()>;
+}
+
+#[derive(Fail, Debug)]
+pub enum PackageError {
+    #[fail(display = "Could not find {}, is it installed?", _0)]
+    NotFound(String),
+    #[fail(display = "\"{}\" failed with {}:\n{}", command, status, stderr)]
+    Failed {
+        command: String,
+        status: ExitStatus,
+        stderr: String,
+    },
+    #[fail(display = "Could not tell which package manager to install {} with, set package_provider in the settings", _0)]
+    NoProvider(String),
+}
+
+/// Runs `invocation`, which has to succeed.
+pub fn run(invocation: &Invocation, context: &Context) -> Result<Finished> {
+    let finished = context.runner.run(invocation).map_err(|error| match error.downcast_ref::<io::Error>() {
+        Some(io_error) if io_error.kind() == io::ErrorKind::NotFound => PackageError::NotFound(invocation.program.clone()).into(),
+        _ => error,
+    })?;
+
+    if !finished.status.success() {
+        return Err(PackageError::Failed {
+            command: invocation.to_string(),
+            status: finished.status,
+            stderr: finished.stderr,
+        }.into());
+    }
+    Ok(finished)
+}
+
+/// What `invocation` prints, which has to succeed.
+pub fn capture(invocation: Invocation, context: &Context) -> Result<String> {
+    Ok(run(&invocation.output(Output::Capture), context)?.stdout)
+}
+
+/// Treats the provider not being there as nothing being installed, so `--explain` works before it is.
+fn unless_missing<T: Default>(result: Result<T>) -> Result<T> {
+    match result {
+        Err(error) => match error.downcast_ref::<PackageError>() {
+            Some(PackageError::NotFound(_)) => Ok(T::default()),
+            _ => homebrew::unless_missing(Err(error)),
+        },
+        ok => ok,
+    }
+}
+
+/// System package managers need root, which dotter gets through sudo unless it already is.
+fn as_root(program: &str) -> Invocation {
+    if is_root() {
+        Invocation::new(program)
+    } else {
+        Invocation::new("sudo").arg(program)
+    }
+}
+
+#[cfg(unix)]
+fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+#[cfg(not(unix))]
+fn is_root() -> bool {
+    false
+}
+
+pub struct Apt;
+
+impl Provider for Apt {
+    fn name(&self) -> &'static str {
+        "apt"
+    }
+
+    fn installed(&self, context: &Context) -> Result<HashSet<String>> {
+        let listing = capture(Invocation::new("dpkg-query").arg("-W").arg("-f=${db:Status-Abbrev} ${Package}\\n"), context)?;
+
+        Ok(listing
+            .lines()
+            .filter(|line| line.starts_with("ii"))
+            .filter_map(|line| line.split_whitespace().nth(1))
+            .map(|package| package.split(':').next().unwrap_or(package).to_string())
+            .collect())
+    }
+
+    fn install(&self, names: &[String], context: &Context) -> Result<()> {
+        run(&as_root("apt-get").arg("install").arg("-y").args(names), context).map(|_| ())
+    }
+
+    fn remove(&self, name: &str, context: &Context) -> Result<()> {
+        run(&as_root("apt-get").arg("remove").arg("-y").arg(name), context).map(|_| ())
+    }
+}
+
+pub struct Dnf;
+
+impl Provider for Dnf {
+    fn name(&self) -> &'static str {
+        "dnf"
+    }
+
+    fn installed(&self, context: &Context) -> Result<HashSet<String>> {
+        let listing = capture(Invocation::new("rpm").arg("-qa").arg("--queryformat").arg("%{NAME}\\n"), context)?;
+        Ok(listing.lines().map(String::from).collect())
+    }
+
+    fn install(&self, names: &[String], context: &Context) -> Result<()> {
+        run(&as_root("dnf").arg("install").arg("-y").args(names), context).map(|_| ())
+    }
+
+    fn remove(&self, name: &str, context: &Context) -> Result<()> {
+        run(&as_root("dnf").arg("remove").arg("-y").arg(name), context).map(|_| ())
+    }
+}
+
+pub struct Pacman;
+
+impl Provider for Pacman {
+    fn name(&self) -> &'static str {
+        "pacman"
+    }
+
+    fn installed(&self, context: &Context) -> Result<HashSet<String>> {
+        let listing = capture(Invocation::new("pacman").arg("-Qq"), context)?;
+        Ok(listing.lines().map(String::from).collect())
+    }
+
+    fn install(&self, names: &[String], context: &Context) -> Result<()> {
+        run(&as_root("pacman").arg("-S").arg("--needed").arg("--noconfirm").args(names), context).map(|_| ())
+    }
+
+    fn remove(&self, name: &str, context: &Context) -> Result<()> {
+        run(&as_root("pacman").arg("-R").arg("--noconfirm").arg(name), context).map(|_| ())
+    }
+}
+
+/// The providers that can be named in the inventory.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProviderKind {
+    Brew,
+    Apt,
+    Dnf,
+    Pacman,
+    Cargo,
+    Npm,
+    Pipx,
+    Go,
+}
+
+impl ProviderKind {
+    const ALL: [ProviderKind; 8] = [
+        ProviderKind::Brew,
+        ProviderKind::Apt,
+        ProviderKind::Dnf,
+        ProviderKind::Pacman,
+        ProviderKind::Cargo,
+        ProviderKind::Npm,
+        ProviderKind::Pipx,
+        ProviderKind::Go,
+    ];
+
+    pub fn provider(self) -> &'static dyn Provider {
+        match self {
+            ProviderKind::Brew => &Homebrew,
+            ProviderKind::Apt => &Apt,
+            ProviderKind::Dnf => &Dnf,
+            ProviderKind::Pacman => &Pacman,
+            ProviderKind::Cargo => &Cargo,
+            ProviderKind::Npm => &Npm,
+            ProviderKind::Pipx => &Pipx,
+            ProviderKind::Go => &Go,
+        }
+    }
+
+    /// The provider whose packages are recorded as `manager` in the state journal.
+    pub fn from_manager(manager: &str) -> Option<ProviderKind> {
+        ProviderKind::ALL.iter().copied().find(|kind| kind.provider().name() == manager)
+    }
+
+    /// The provider for this machine: Homebrew on macOS, otherwise the first of apt, dnf and
+    /// pacman that is on the `PATH`, falling back to Homebrew if that is installed.
+    pub fn detect(context: &Context) -> Option<ProviderKind> {
+        if cfg!(target_os = "macos") {
+            return Some(ProviderKind::Brew);
+        }
+
+        let system = [(ProviderKind::Apt, "apt-get"), (ProviderKind::Dnf, "dnf"), (ProviderKind::Pacman, "pacman")];
+        system
+            .iter()
+            .find(|(_, program)| which(program, &context.environment).is_some())
+            .map(|(kind, _)| *kind)
+            .or_else(|| match homebrew::locate(context) {
+                Ok(Some(_)) => Some(ProviderKind::Brew),
+                _ => None,
+            })
+    }
+}
+
+/// A package to install with a specific provider, instead of the one for this machine.
+#[derive(Deserialize, Debug)]
+pub struct ProvidedPackage {
+    name: String,
+    provider: ProviderKind,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum Package {
+    Simple(String),
+    Provided(ProvidedPackage),
+}
+
+impl Package {
+    fn name(&self) -> &str {
+        match self {
+            Package::Simple(name) => name,
+            Package::Provided(ProvidedPackage { name, .. }) => name,
+        }
+    }
+
+    /// The provider named on the package, else the one of its step, else in the settings, else
+    /// the one for this machine.
+    fn provider(&self, step: Option<ProviderKind>, context: &Context) -> Result<&'static dyn Provider> {
+        self.kind(step, context)
+            .map(ProviderKind::provider)
+            .ok_or_else(|| PackageError::NoProvider(self.name().to_string()).into())
+    }
+
+    /// The provider the package names, else the one of its step, the settings or this machine.
+    fn kind(&self, step: Option<ProviderKind>, context: &Context) -> Option<ProviderKind> {
+        match self {
+            Package::Provided(ProvidedPackage { provider, .. }) => Some(*provider),
+            Package::Simple(_) => step.or(context.settings.package_provider).or_else(|| ProviderKind::detect(context)),
+        }
+    }
+}
+
+/// The packages of a group, installed together per provider like brews are.
+#[derive(Deserialize, Debug, Default)]
+#[serde(transparent)]
+pub struct Packages {
+    packages: Vec<Package>,
+
+    /// Set for the steps of one ecosystem, like `cargo`, instead of the system package manager.
+    #[serde(skip)]
+    provider: Option<ProviderKind>,
+}
+
+/// Reads packages that are installed with `provider` unless they name another one.
+pub fn provided_by<'de, D: Deserializer<'de>>(deserializer: D, provider: ProviderKind) -> std::result::Result<Packages, D::Error> {
+    let packages = Vec::deserialize(deserializer)?;
+    Ok(Packages { packages, provider: Some(provider) })
+}
+
+impl Packages {
+    /// Whether any of the packages would be installed with Homebrew.
+    pub fn needs_homebrew(&self, context: &Context) -> bool {
+        self.packages.iter().any(|package| package.kind(self.provider, context) == Some(ProviderKind::Brew))
+    }
+
+    /// The packages grouped by their provider, in the order the providers first appear.
+    fn by_provider(&self, context: &Context) -> Result<Vec<(&'static dyn Provider, Vec<&Package>)>> {
+        let mut grouped: Vec<(&'static dyn Provider, Vec<&Package>)> = Vec::new();
+        for package in &self.packages {
+            let provider = package.provider(self.provider, context)?;
+            match grouped.iter_mut().find(|(known, _)| known.name() == provider.name()) {
+                Some((_, packages)) => packages.push(package),
+                None => grouped.push((provider, vec![package])),
+            }
+        }
+        Ok(grouped)
+    }
+}
+
+/// Installs `names` with `install`, all together and then one at a time to find out which of them
+/// failed. A failed attempt may still have installed some of them, so those that `installed` reports
+/// afterwards are recorded for `manager` too.
+pub fn install_together<I, L>(manager: &str, names: &[String], context: &Context, install: I, installed: L) -> Result<()>
+where
+    I: Fn(&[String]) -> Result<()>,
+    L: Fn(&[String]) -> Result<Vec<String>>,
+{
+    if names.len() > 1 && install(names).is_ok() {
+        for name in names {
+            context.state.borrow_mut().installed_package(manager, name);
+        }
+        return Ok(());
+    }
+
+    for (index, name) in names.iter().enumerate() {
+        if let Err(error) = install(std::slice::from_ref(name)) {
+            let rest = &names[index + 1..];
+            if !rest.is_empty() {
+                for name in installed(rest)? {
+                    context.state.borrow_mut().installed_package(manager, &name);
+                }
+            }
+            return Err(error);
+        }
+        context.state.borrow_mut().installed_package(manager, name);
+    }
+    Ok(())
+}
+
+/// Which of `names` `provider` lists as installed.
+fn installed_of(provider: &dyn Provider, names: &[String], context: &Context) -> Result<Vec<String>> {
+    let installed = provider.installed(context)?;
+    Ok(names.iter().filter(|name| installed.contains(provider.installed_as(name))).cloned().collect())
+}
+
+impl Packages {
+    fn apply(&self, context: &Context) -> Result<()> {
+        for (provider, packages) in self.by_provider(context)? {
+            let installed = provider.installed(context)?;
+
+            let mut missing = Vec::new();
+            for package in packages {
+                if installed.contains(provider.installed_as(package.name())) {
+                    context.state.borrow_mut().found_package(provider.name(), package.name());
+                } else {
+                    missing.push(package.name().to_string());
+                }
+            }
+            install_together(
+                provider.name(),
+                &missing,
+                context,
+                |names| provider.install(names, context),
+                |names| installed_of(provider, names, context),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Command for Packages {
+    fn execute(&self, context: &Context) -> Result<()> {
+        let packages = self
+            .packages
+            .iter()
+            .map(|package| Ok((package.provider(self.provider, context)?.name(), package.name().to_string())))
+            .collect::<Result<Vec<_>>>()?;
+
+        transaction::contain(&packages, context, || self.apply(context), |manager, name| uninstall(manager, name, context))
+    }
+
+    fn rollback(&self, context: &Context) -> Result<()> {
+        for package in self.packages.iter().rev() {
+            let provider = package.provider(self.provider, context)?;
+            if context.state.borrow().installed_by_dotter(provider.name(), package.name()) {
+                provider.remove(package.name(), context)?;
+                context.state.borrow_mut().removed_package(provider.name(), package.name());
+            }
+        }
+        Ok(())
+    }
+
+    fn explain(&self, context: &Context) -> Result<Vec<Explanation>> {
+        let mut explanations = Vec::new();
+        for (provider, packages) in self.by_provider(context)? {
+            let installed = unless_missing(provider.installed(context))?;
+
+            for package in packages {
+                let name = package.name();
+                let installed_by_dotter = context.state.borrow().installed_by_dotter(provider.name(), name);
+                let message = match (installed.contains(provider.installed_as(name)), &context.direction) {
+                    (true, Direction::Execute) => format!("{} is already installed, nothing to do", name),
+                    (false, Direction::Execute) => format!("Will install {} with {}", name, provider.name()),
+                    (true, Direction::Rollback) if installed_by_dotter => format!("Will uninstall {} with {}.", name, provider.name()),
+                    (true, Direction::Rollback) => format!("{} was not installed by dotter, leaving it alone.", name),
+                    (false, Direction::Rollback) => format!("{} is not installed, nothing to do.", name),
+                };
+                explanations.push(Explanation::new(message));
+            }
+        }
+
+        if let Direction::Rollback = context.direction {
+            explanations.reverse();
+        }
+        Ok(explanations)
+    }
+
+    fn steps(&self) -> Vec<&dyn Command> {
+        vec![self]
+    }
+
+    fn resources(&self, context: &Context) -> Vec<Resource> {
+        self.packages
+            .iter()
+            .filter_map(|package| {
+                let provider = package.provider(self.provider, context).ok()?;
+                Some(Resource::Package(provider.name().to_string(), package.name().to_string()))
+            })
+            .collect()
+    }
+}
+
+/// Uninstalls a package recorded in the state journal, whichever step installed it.
+pub fn uninstall(manager: &str, name: &str, context: &Context) -> Result<()> {
+    match manager {
+        "brew" | "brew-cask" | "brew-tap" => homebrew::uninstall(manager, name, context),
+        _ => match ProviderKind::from_manager(manager) {
+            Some(kind) => kind.provider().remove(name, context),
+            None => Err(format_err!("Do not know how to uninstall {} with '{}'", name, manager)),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::fake::FakeRunner;
+    use crate::settings::Settings;
+    use maplit::hashmap;
+    use std::path::PathBuf;
+    use std::rc::Rc;
+
+    fn faked(provider: ProviderKind) -> (Rc<FakeRunner>, Context) {
+        let runner = Rc::new(FakeRunner::default());
+        let context = Context {
+            runner: runner.clone(),
+            settings: Settings { package_provider: Some(provider), ..Settings::default() },
+            ..Context::default()
+        };
+        (runner, context)
+    }
+
+    fn packages(json: &str) -> Packages {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn sudo(command: &str) -> String {
+        if is_root() {
+            command.to_string()
+        } else {
+            format!("sudo {}", command)
+        }
+    }
+
+    #[test]
+    fn missing_packages_are_installed_together() {
+        let (runner, context) = faked(ProviderKind::Apt);
+        runner.print("dpkg-query -W -f=${db:Status-Abbrev} ${Package}\\n", "ii  git\nrc  vim\nii  libc6:amd64\n");
+
+        packages(r#"["git", "vim", "ripgrep", "libc6"]"#).execute(&context).unwrap();
+
+        assert_eq!(runner.commands(), vec![
+            "dpkg-query -W -f=${db:Status-Abbrev} ${Package}\\n".to_string(),
+            sudo("apt-get install -y vim ripgrep"),
+        ]);
+        let state = context.state.borrow();
+        assert!(state.installed_by_dotter("apt", "ripgrep"));
+        assert!(!state.installed_by_dotter("apt", "git"));
+    }
+
+    #[test]
+    fn a_failed_batch_is_retried_one_package_at_a_time() {
+        let (runner, context) = faked(ProviderKind::Pacman);
+        runner.respond(&sudo("pacman -S --needed --noconfirm ripgrep fdd bat"), 1, "error: target not found: fdd");
+        runner.respond(&sudo("pacman -S --needed --noconfirm fdd"), 1, "error: target not found: fdd");
+
+        runner.print("pacman -Qq", "");
+        runner.print("pacman -Qq", "ripgrep\nbat\n");
+
+        let error = packages(r#"["ripgrep", "fdd", "bat"]"#).execute(&context).unwrap_err();
+
+        assert_eq!(error.to_string(), format!("\"{}\" failed with exit status: 1:\nerror: target not found: fdd", sudo("pacman -S --needed --noconfirm fdd")));
+        let state = context.state.borrow();
+        assert!(state.installed_by_dotter("pacman", "ripgrep"));
+        assert!(state.installed_by_dotter("pacman", "bat"));
+        assert!(!state.installed_by_dotter("pacman", "fdd"));
+    }
+
+    #[test]
+    fn brew_packages_are_retried_one_at_a_time_too() {
+        let (runner, context) = faked(ProviderKind::Brew);
+        runner.respond("brew install ripgrep fdd", 1, "Error: disk full");
+        runner.respond("brew install fdd", 1, "Error: No available formula with the name \"fdd\".");
+
+        let error = packages(r#"["ripgrep", "fdd"]"#).execute(&context).unwrap_err();
+
+        assert_eq!(error.to_string(), "Homebrew does not know a package called fdd");
+        assert!(runner.commands().contains(&"brew install ripgrep".to_string()));
+        assert!(context.state.borrow().installed_by_dotter("brew", "ripgrep"));
+    }
+
+    #[test]
+    fn explain_works_before_the_provider_is_installed() {
+        let context = Context {
+            brew: PathBuf::from("/nonexistent/brew"),
+            settings: Settings { package_provider: Some(ProviderKind::Brew), ..Settings::default() },
+            ..Context::default()
+        };
+
+        let explanations = packages(r#"["ripgrep"]"#).explain(&context).unwrap();
+
+        assert_eq!(explanations[0].message, "Will install ripgrep with brew");
+    }
+
+    #[test]
+    fn packages_can_name_their_provider() {
+        let (runner, context) = faked(ProviderKind::Apt);
+        runner.print("rpm -qa --queryformat %{NAME}\\n", "git\n");
+
+        let explanations = packages(r#"[{"name": "git", "provider": "dnf"}, {"name": "fd-find", "provider": "dnf"}]"#)
+            .explain(&context)
+            .unwrap();
+
+        let messages: Vec<String> = explanations.into_iter().map(|explanation| explanation.message).collect();
+        assert_eq!(messages, vec!["git is already installed, nothing to do", "Will install fd-find with dnf"]);
+        assert_eq!(runner.commands(), vec!["rpm -qa --queryformat %{NAME}\\n"]);
+    }
+
+    #[test]
+    fn only_packages_installed_by_dotter_are_removed() {
+        let (runner, context) = faked(ProviderKind::Brew);
+        context.state.borrow_mut().installed_package("brew", "ripgrep");
+        context.state.borrow_mut().found_package("brew", "git");
+
+        packages(r#"["git", "ripgrep"]"#).rollback(&context).unwrap();
+
+        assert_eq!(runner.commands(), vec!["brew remove ripgrep"]);
+        assert!(!context.state.borrow().installed_by_dotter("brew", "ripgrep"));
+    }
+
+    #[test]
+    #[cfg(not(target_os = "macos"))]
+    fn the_provider_is_detected_from_the_path() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::File::create(dir.path().join("pacman")).unwrap();
+        let context = Context {
+            environment: hashmap! { "PATH".to_string() => dir.path().display().to_string() },
+            ..Context::default()
+        };
+
+        assert_eq!(ProviderKind::detect(&context), Some(ProviderKind::Pacman));
+    }
+}
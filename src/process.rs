@@ -0,0 +1,437 @@
+use crate::Result;
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::PathBuf;
+use std::process::{self, Child, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How many lines of stderr are kept to explain a failing command.
+const STDERR_TAIL: usize = 10;
+
+/// How long an external command may take, and how often it is tried before giving up.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct RetryPolicy {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_secs: Option<u64>,
+
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub retries: u32,
+
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub retry_delay_secs: u64,
+}
+
+/// For leaving settings out when serializing, if they are not set.
+pub fn is_default<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+impl RetryPolicy {
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout_secs.map(Duration::from_secs)
+    }
+}
+
+#[derive(Fail, Debug)]
+#[fail(display = "\"{}\" timed out after {} seconds", command, seconds)]
+pub struct TimedOut {
+    command: String,
+    seconds: u64,
+}
+
+#[derive(Fail, Debug)]
+#[fail(display = "Attempt {} of {} failed: {}", attempt, attempts, cause)]
+pub struct AttemptFailed {
+    attempt: u32,
+    attempts: u32,
+    cause: failure::Error,
+}
+
+/// Calls `attempt` until it succeeds or the retries of `policy` are used up.
+pub fn retry<F: FnMut() -> Result<()>>(policy: &RetryPolicy, mut attempt: F) -> Result<()> {
+    let attempts = policy.retries + 1;
+
+    for current in 1..=attempts {
+        let cause = match attempt() {
+            Ok(()) => return Ok(()),
+            Err(cause) => cause,
+        };
+
+        if current == attempts {
+            if attempts == 1 {
+                return Err(cause);
+            }
+            return Err(AttemptFailed { attempt: current, attempts, cause }.into());
+        }
+
+        eprintln!("Attempt {} of {} failed: {}, retrying in {} seconds", current, attempts, cause, policy.retry_delay_secs);
+        thread::sleep(Duration::from_secs(policy.retry_delay_secs));
+    }
+
+    unreachable!("the last attempt always returns")
+}
+
+/// An external command to run, described rather than built so a `Runner` can decide what to do with it.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Invocation {
+    pub program: String,
+    pub args: Vec<String>,
+    pub cwd: Option<PathBuf>,
+    /// The complete environment for the command, it inherits dotter's if `None`.
+    pub environment: Option<HashMap<String, String>>,
+    pub timeout: Option<Duration>,
+    pub output: Output,
+}
+
+/// What happens to what a command prints.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Output {
+    /// Passed through to the user.
+    #[default]
+    Show,
+    /// Thrown away.
+    Discard,
+    /// Stdout is collected into `Finished::stdout`, stderr is treated like `Show`.
+    Capture,
+}
+
+impl Invocation {
+    pub fn new<S: Into<String>>(program: S) -> Invocation {
+        Invocation {
+            program: program.into(),
+            ..Invocation::default()
+        }
+    }
+
+    pub fn arg<S: Into<String>>(mut self, arg: S) -> Invocation {
+        self.args.push(arg.into());
+        self
+    }
+
+    pub fn args<I: IntoIterator<Item = S>, S: Into<String>>(mut self, args: I) -> Invocation {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn cwd<P: Into<PathBuf>>(mut self, cwd: P) -> Invocation {
+        self.cwd = Some(cwd.into());
+        self
+    }
+
+    pub fn environment(mut self, environment: HashMap<String, String>) -> Invocation {
+        self.environment = Some(environment);
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Invocation {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn output(mut self, output: Output) -> Invocation {
+        self.output = output;
+        self
+    }
+}
+
+impl fmt::Display for Invocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.program)?;
+        for arg in &self.args {
+            write!(f, " {}", arg)?;
+        }
+        Ok(())
+    }
+}
+
+/// How a command ended.
+#[derive(Debug)]
+pub struct Finished {
+    pub status: ExitStatus,
+    /// Everything the command printed, if its output was captured.
+    pub stdout: String,
+    /// The last few lines the command wrote to stderr.
+    pub stderr: String,
+}
+
+/// Finds `program` in the directories of the `PATH` in `environment`.
+pub fn which(program: &str, environment: &HashMap<String, String>) -> Option<PathBuf> {
+    let path = environment.get("PATH")?;
+    env::split_paths(path)
+        .map(|directory| directory.join(program))
+        .find(|candidate| candidate.is_absolute() && candidate.is_file())
+}
+
+/// Runs every external command dotter needs, so tests can replace the real tools.
+pub trait Runner {
+    fn run(&self, invocation: &Invocation) -> Result<Finished>;
+}
+
+/// Runs commands for real.
+pub struct SystemRunner;
+
+impl Runner for SystemRunner {
+    fn run(&self, invocation: &Invocation) -> Result<Finished> {
+        let mut command = process::Command::new(&invocation.program);
+        command.args(&invocation.args);
+        if let Some(cwd) = &invocation.cwd {
+            command.current_dir(cwd);
+        }
+        if let Some(environment) = &invocation.environment {
+            command.env_clear().envs(environment);
+        }
+
+        run(&mut command, invocation.timeout, &invocation.to_string(), invocation.output)
+    }
+}
+
+/// Runs `command` to completion or until `timeout` passes. Unless discarded, its stderr is passed
+/// through as it comes, but the end of it is held on to for error messages.
+pub fn run(command: &mut process::Command, timeout: Option<Duration>, description: &str, output: Output) -> Result<Finished> {
+    match output {
+        Output::Show => command.stderr(Stdio::piped()),
+        Output::Discard => command.stdout(Stdio::null()).stderr(Stdio::null()),
+        Output::Capture => command.stdout(Stdio::piped()).stderr(Stdio::piped()),
+    };
+    let mut child = spawn(command)?;
+
+    let stderr = child.stderr.take().map(|stderr| {
+        thread::spawn(move || {
+            let mut tail = VecDeque::with_capacity(STDERR_TAIL);
+            for line in BufReader::new(stderr).split(b'\n') {
+                let line = match line {
+                    Ok(line) => String::from_utf8_lossy(&line).into_owned(),
+                    Err(_) => break,
+                };
+                eprintln!("{}", line);
+                if tail.len() == STDERR_TAIL {
+                    tail.pop_front();
+                }
+                tail.push_back(line);
+            }
+            tail
+        })
+    });
+
+    let stdout = child.stdout.take().map(|mut stdout| {
+        thread::spawn(move || {
+            let mut collected = String::new();
+            let _ = stdout.read_to_string(&mut collected);
+            collected
+        })
+    });
+
+    let status = wait(&mut child, timeout, description)?;
+
+    Ok(Finished {
+        status,
+        stdout: stdout.and_then(|reader| reader.join().ok()).unwrap_or_default(),
+        stderr: stderr
+            .and_then(|reader| reader.join().ok())
+            .map(|tail| Vec::from(tail).join("\n"))
+            .unwrap_or_default(),
+    })
+}
+
+/// Starts `command` in its own process group, so it can be killed along with everything it started.
+pub fn spawn(command: &mut process::Command) -> io::Result<Child> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        command.process_group(0);
+    }
+
+    command.spawn()
+}
+
+/// Waits for a child started with `spawn`, killing its whole process group once `timeout` has passed.
+pub fn wait(child: &mut Child, timeout: Option<Duration>, command: &str) -> Result<ExitStatus> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return Ok(child.wait()?),
+    };
+
+    let started = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+
+        if started.elapsed() >= timeout {
+            kill(child)?;
+            child.wait()?;
+            return Err(TimedOut {
+                command: command.to_string(),
+                seconds: timeout.as_secs(),
+            }.into());
+        }
+
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[cfg(unix)]
+fn kill(child: &mut Child) -> io::Result<()> {
+    // The child leads its own process group, a negative pid signals every process in it.
+    if unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(unix))]
+fn kill(child: &mut Child) -> io::Result<()> {
+    child.kill()
+}
+
+/// Stands in for the real tools in tests: it records every invocation and answers with scripted outputs.
+#[cfg(test)]
+pub mod fake {
+    use super::*;
+    use std::cell::RefCell;
+    use std::os::unix::process::ExitStatusExt;
+
+    #[derive(Default)]
+    pub struct FakeRunner {
+        responses: RefCell<Vec<(String, i32, String, String)>>,
+        invocations: RefCell<Vec<Invocation>>,
+    }
+
+    impl FakeRunner {
+        /// Answers `command_line` with the given exit code and stderr. Scripting the same command line
+        /// more than once plays the answers in order, the last one sticks.
+        pub fn respond(&self, command_line: &str, code: i32, stderr: &str) {
+            self.responses.borrow_mut().push((command_line.to_string(), code, String::new(), stderr.to_string()));
+        }
+
+        /// Answers `command_line` by succeeding and printing `stdout`.
+        pub fn print(&self, command_line: &str, stdout: &str) {
+            self.responses.borrow_mut().push((command_line.to_string(), 0, stdout.to_string(), String::new()));
+        }
+
+        /// Every command line that was run, in order.
+        pub fn commands(&self) -> Vec<String> {
+            self.invocations.borrow().iter().map(ToString::to_string).collect()
+        }
+
+        pub fn invocations(&self) -> Vec<Invocation> {
+            self.invocations.borrow().clone()
+        }
+    }
+
+    impl Runner for FakeRunner {
+        fn run(&self, invocation: &Invocation) -> Result<Finished> {
+            self.invocations.borrow_mut().push(invocation.clone());
+
+            let command_line = invocation.to_string();
+            let mut responses = self.responses.borrow_mut();
+            let matching: Vec<usize> = (0..responses.len()).filter(|&i| responses[i].0 == command_line).collect();
+
+            let (code, stdout, stderr) = match matching.first() {
+                None => (0, String::new(), String::new()),
+                Some(&first) if matching.len() > 1 => {
+                    let (_, code, stdout, stderr) = responses.remove(first);
+                    (code, stdout, stderr)
+                }
+                Some(&only) => {
+                    let (_, code, stdout, stderr) = &responses[only];
+                    (*code, stdout.clone(), stderr.clone())
+                }
+            };
+
+            Ok(Finished {
+                status: ExitStatus::from_raw(code << 8),
+                stdout,
+                stderr,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use maplit::hashmap;
+
+    #[test]
+    fn it_kills_commands_that_take_too_long() {
+        let started = Instant::now();
+        let mut child = spawn(process::Command::new("sh").arg("-c").arg("sleep 10 & sleep 10")).unwrap();
+
+        let error = wait(&mut child, Some(Duration::from_secs(1)), "sleep 10").unwrap_err();
+
+        assert_eq!(error.to_string(), "\"sleep 10\" timed out after 1 seconds");
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn it_waits_for_commands_that_finish_in_time() {
+        let mut child = spawn(process::Command::new("sh").arg("-c").arg("exit 3")).unwrap();
+
+        let status = wait(&mut child, Some(Duration::from_secs(5)), "exit 3").unwrap();
+
+        assert_eq!(status.code(), Some(3));
+    }
+
+    #[test]
+    fn it_keeps_the_end_of_stderr() {
+        let finished = run(
+            process::Command::new("sh").arg("-c").arg("for i in $(seq 1 20); do echo line $i >&2; done; exit 1"),
+            None,
+            "noisy",
+            Output::Show,
+        ).unwrap();
+
+        assert_eq!(finished.status.code(), Some(1));
+        assert_eq!(finished.stderr, "line 11\nline 12\nline 13\nline 14\nline 15\nline 16\nline 17\nline 18\nline 19\nline 20");
+    }
+
+    #[test]
+    fn the_system_runner_runs_invocations() {
+        let dir = tempfile::tempdir().unwrap();
+        let invocation = Invocation::new("sh")
+            .args(vec!["-c", "echo \"$GREETING from ${PWD##*/}\" >&2; exit 4"])
+            .cwd(dir.path())
+            .environment(hashmap! { "GREETING".to_string() => "hello".to_string() });
+
+        let finished = SystemRunner.run(&invocation).unwrap();
+
+        let name = dir.path().file_name().unwrap().to_string_lossy();
+        assert_eq!(finished.status.code(), Some(4));
+        assert_eq!(finished.stderr, format!("hello from {}", name));
+    }
+
+    #[test]
+    fn it_retries_until_an_attempt_succeeds() {
+        let attempts = Cell::new(0);
+        let policy = RetryPolicy { retries: 2, ..RetryPolicy::default() };
+
+        retry(&policy, || {
+            attempts.set(attempts.get() + 1);
+            if attempts.get() < 3 { Err(format_err!("not yet")) } else { Ok(()) }
+        }).unwrap();
+
+        assert_eq!(attempts.get(), 3);
+    }
+
+    #[test]
+    fn it_reports_which_attempt_failed() {
+        let policy = RetryPolicy { retries: 1, ..RetryPolicy::default() };
+
+        let error = retry(&policy, || Err(format_err!("network is down"))).unwrap_err();
+
+        assert_eq!(error.to_string(), "Attempt 2 of 2 failed: network is down");
+    }
+
+    #[test]
+    fn without_retries_the_error_is_passed_on() {
+        let error = retry(&RetryPolicy::default(), || Err(format_err!("network is down"))).unwrap_err();
+
+        assert_eq!(error.to_string(), "network is down");
+    }
+}
@@ -0,0 +1,194 @@
+use crate::packages;
+use crate::state::{LinkRecord, PackageRecord, Resource};
+use crate::symlinks;
+use crate::{Command, Context, Explanation, Result};
+use std::path::PathBuf;
+
+/// Everything dotter applied earlier for this inventory that it no longer declares.
+pub struct Prune {
+    links: Vec<LinkRecord>,
+    packages: Vec<PackageRecord>,
+}
+
+impl Prune {
+    pub fn plan(inventory: &dyn Command, context: &Context) -> Prune {
+        let declared: Vec<Resource> = inventory
+            .steps()
+            .iter()
+            .flat_map(|step| step.resources(context))
+            .collect();
+
+        let state = context.state.borrow();
+        let ours = |inventories: &[PathBuf]| inventories.iter().any(|inventory| Some(inventory.as_path()) == state.inventory());
+        Prune {
+            links: state
+                .links()
+                .iter()
+                .filter(|link| ours(&link.inventories))
+                .filter(|link| !declared.contains(&Resource::Link(link.to.clone())))
+                .cloned()
+                .collect(),
+            packages: state
+                .packages()
+                .iter()
+                .filter(|package| ours(&package.inventories))
+                .filter(|package| !declared.contains(&Resource::Package(package.manager.clone(), package.name.clone())))
+                .cloned()
+                .collect(),
+        }
+    }
+
+    pub fn execute(&self, context: &Context) -> Result<()> {
+        for link in &self.links {
+            if shared(&link.inventories) {
+                context.state.borrow_mut().disowned_link(&link.to);
+                continue;
+            }
+            symlinks::unlink(&link.to, link.backup.as_deref())?;
+            context.state.borrow_mut().removed_link(&link.to);
+        }
+
+        for package in &self.packages {
+            if shared(&package.inventories) {
+                context.state.borrow_mut().disowned_package(&package.manager, &package.name);
+                continue;
+            }
+            if package.installed_by_dotter {
+                packages::uninstall(&package.manager, &package.name, context)?;
+            }
+            context.state.borrow_mut().removed_package(&package.manager, &package.name);
+        }
+
+        Ok(())
+    }
+
+    pub fn explain(&self) -> Vec<Explanation> {
+        let mut explanations = Vec::new();
+
+        for link in &self.links {
+            let message = if shared(&link.inventories) {
+                format!("Leaving symmlink to {}, another inventory still uses it", link.to.display())
+            } else if is_symlink(link) {
+                format!("Removing symmlink to {}, it is no longer in the inventory", link.to.display())
+            } else {
+                format!("Forgetting symmlink to {}, it is already gone", link.to.display())
+            };
+            explanations.push(Explanation::new(message));
+        }
+
+        for package in &self.packages {
+            let message = if shared(&package.inventories) {
+                format!("Leaving {}, another inventory still uses it", package.name)
+            } else if package.installed_by_dotter {
+                format!("Will uninstall {}, it is no longer in the inventory", package.name)
+            } else {
+                format!("Forgetting {}, it was not installed by dotter", package.name)
+            };
+            explanations.push(Explanation::new(message));
+        }
+
+        if explanations.is_empty() {
+            explanations.push(Explanation::new("Nothing to prune"));
+        }
+
+        explanations
+    }
+}
+
+/// Whether inventories other than the one being pruned applied it too.
+fn shared(inventories: &[PathBuf]) -> bool {
+    inventories.len() > 1
+}
+
+fn is_symlink(link: &LinkRecord) -> bool {
+    link.to
+        .symlink_metadata()
+        .map(|metadata| metadata.file_type().is_symlink())
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::State;
+    use crate::symlinks::Symlink;
+    use std::cell::RefCell;
+    use std::fs::File;
+    use tempfile::tempdir;
+
+    #[test]
+    fn links_dropped_from_the_inventory_are_removed() {
+        let dir = tempdir().unwrap();
+        File::create(dir.path().join("vimrc")).unwrap();
+        File::create(dir.path().join("zshrc")).unwrap();
+
+        let context = Context {
+            working_directory: dir.path().to_path_buf(),
+            state: applied_by("/dotfiles/inventory.json"),
+            ..Context::default()
+        };
+
+        let before: Vec<Symlink> = serde_json::from_str(r#"[
+            {"from": "vimrc", "to": ".vimrc"},
+            {"from": "zshrc", "to": ".zshrc"}
+        ]"#).unwrap();
+        before.execute(&context).unwrap();
+
+        let after: Vec<Symlink> = serde_json::from_str(r#"[
+            {"from": "vimrc", "to": ".vimrc"}
+        ]"#).unwrap();
+        let prune = Prune::plan(&after, &context);
+
+        let explanations = prune.explain();
+        let expected = format!("Removing symmlink to {}, it is no longer in the inventory", dir.path().join(".zshrc").display());
+        assert_eq!(explanations.len(), 1);
+        assert_eq!(explanations[0].message, expected);
+
+        prune.execute(&context).unwrap();
+
+        assert!(dir.path().join(".vimrc").exists());
+        assert!(!dir.path().join(".zshrc").exists());
+        assert!(Prune::plan(&after, &context).links.is_empty());
+    }
+
+    fn applied_by(inventory: &str) -> RefCell<State> {
+        RefCell::new(State::default().applied_by(PathBuf::from(inventory)))
+    }
+
+    #[test]
+    fn packages_that_were_already_installed_are_only_forgotten() {
+        let context = Context { state: applied_by("/dotfiles/inventory.json"), ..Context::default() };
+        context.state.borrow_mut().found_package("brew", "git");
+
+        let prune = Prune::plan(&Vec::<Symlink>::new(), &context);
+
+        assert_eq!(prune.explain()[0].message, "Forgetting git, it was not installed by dotter");
+
+        prune.execute(&context).unwrap();
+
+        assert!(context.state.borrow().packages().is_empty());
+    }
+
+    #[test]
+    fn what_other_inventories_applied_is_left_alone() {
+        let work = Context { state: applied_by("/work/inventory.json"), ..Context::default() };
+        work.state.borrow_mut().installed_package("brew", "kubectl");
+        work.state.borrow_mut().installed_package("brew", "git");
+
+        let state = work.state.into_inner().applied_by(PathBuf::from("/home/inventory.json"));
+        let home = Context { state: RefCell::new(state), ..Context::default() };
+        home.state.borrow_mut().found_package("brew", "git");
+
+        let prune = Prune::plan(&Vec::<Symlink>::new(), &home);
+
+        assert_eq!(prune.explain().len(), 1);
+        assert_eq!(prune.explain()[0].message, "Leaving git, another inventory still uses it");
+
+        prune.execute(&home).unwrap();
+
+        let state = home.state.borrow();
+        assert!(state.installed_by_dotter("brew", "kubectl"));
+        assert!(state.installed_by_dotter("brew", "git"));
+        assert_eq!(state.packages().iter().find(|package| package.name == "git").unwrap().inventories, [PathBuf::from("/work/inventory.json")]);
+    }
+}
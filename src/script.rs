@@ -0,0 +1,258 @@
+use crate::process::Invocation;
+use crate::{Command, Context, Direction, Explanation, Result};
+use std::path::Path;
+
+/// A script file from the dotfiles repository, run with `DOTTER_*` variables describing the run.
+///
+/// Rollbacks skip it unless it has an `undo` script, or says it handles `DOTTER_DIRECTION` itself
+/// with `rollback`.
+#[derive(Deserialize, Debug, Default)]
+pub struct Script {
+    /// Relative to the working directory.
+    path: String,
+
+    #[serde(default)]
+    args: Vec<String>,
+
+    /// Run with the same arguments instead of `path` on rollback.
+    #[serde(default)]
+    undo: Option<String>,
+
+    /// Run `path` again on rollback, with `DOTTER_DIRECTION` set to `rollback`.
+    #[serde(default)]
+    rollback: bool,
+
+    /// Name of the group the script belongs to, filled in once the inventory is read.
+    #[serde(skip)]
+    group: String,
+}
+
+impl Script {
+    pub fn set_group(&mut self, group: &str) {
+        self.group = group.to_string();
+    }
+
+    /// The script to run on rollback, if there is one.
+    fn undo(&self) -> Option<&str> {
+        match &self.undo {
+            Some(undo) => Some(undo),
+            None if self.rollback => Some(&self.path),
+            None => None,
+        }
+    }
+
+    fn run(&self, path: &str, context: &Context) -> Result<()> {
+        let location = context.interpolate(path);
+        check(&location)?;
+
+        let mut environment = context.environment.clone();
+        environment.insert("DOTTER_GROUP".to_string(), self.group.clone());
+        environment.insert("DOTTER_DIRECTION".to_string(), context.direction.to_string());
+        environment.insert("DOTTER_WORKING_DIR".to_string(), context.working_directory.display().to_string());
+
+        let invocation = Invocation::new(location.display().to_string())
+            .args(self.args.iter().cloned())
+            .cwd(&context.working_directory)
+            .environment(environment);
+        let status = context.runner.run(&invocation)?.status;
+
+        if !status.success() {
+            return Err(format_err!("Script {} failed with {}", location.display(), status));
+        }
+        Ok(())
+    }
+}
+
+/// Makes sure `location` is something that can be run.
+fn check(location: &Path) -> Result<()> {
+    let metadata = location
+        .metadata()
+        .map_err(|_| format_err!("Script {} does not exist", location.display()))?;
+
+    if !metadata.is_file() || !is_executable(&metadata) {
+        return Err(format_err!("Script {} is not executable", location.display()));
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn is_executable(metadata: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_metadata: &std::fs::Metadata) -> bool {
+    true
+}
+
+impl Command for Script {
+    fn execute(&self, context: &Context) -> Result<()> {
+        self.run(&self.path, context)
+    }
+
+    fn rollback(&self, context: &Context) -> Result<()> {
+        match self.undo() {
+            Some(undo) => self.run(undo, context),
+            None => Ok(()),
+        }
+    }
+
+    fn explain(&self, context: &Context) -> Result<Vec<Explanation>> {
+        let path = match context.direction {
+            Direction::Execute => &self.path,
+            Direction::Rollback => match self.undo() {
+                Some(undo) => undo,
+                None => return Ok(vec![Explanation::new(format!("No undo for script \"{}\", skipping it", self.path))]),
+            },
+        };
+        check(&context.interpolate(path))?;
+
+        let mut invocation = path.to_string();
+        for arg in &self.args {
+            invocation.push(' ');
+            invocation.push_str(arg);
+        }
+
+        let message = match context.direction {
+            Direction::Execute => format!("About to run script \"{}\"", invocation),
+            Direction::Rollback => format!("About to run script \"{}\" to roll back", invocation),
+        };
+        Ok(vec![Explanation::new(message)])
+    }
+
+    fn steps(&self) -> Vec<&dyn Command> {
+        vec![self]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::{tempdir, TempDir};
+
+    fn given_a_script(name: &str, body: &str, mode: u32) -> TempDir {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join(name);
+        fs::write(&path, body).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+        dir
+    }
+
+    #[test]
+    fn it_runs_the_script_with_dotter_variables() {
+        let dir = given_a_script("setup.sh", "#!/bin/sh\necho \"$DOTTER_GROUP $DOTTER_DIRECTION $DOTTER_WORKING_DIR $1\" > out\n", 0o755);
+        let context = Context {
+            working_directory: dir.path().to_path_buf(),
+            ..Context::default()
+        };
+
+        let mut script = Script {
+            path: "setup.sh".to_string(),
+            args: vec!["fast".to_string()],
+            ..Script::default()
+        };
+        script.set_group("vim");
+
+        script.execute(&context).unwrap();
+
+        let out = fs::read_to_string(dir.path().join("out")).unwrap();
+        assert_eq!(out, format!("vim run {} fast\n", dir.path().display()));
+    }
+
+    #[test]
+    fn it_explains_what_it_will_run() {
+        let dir = given_a_script("setup.sh", "#!/bin/sh\n", 0o755);
+        let context = Context {
+            working_directory: dir.path().to_path_buf(),
+            direction: Direction::Rollback,
+            ..Context::default()
+        };
+
+        let script = Script {
+            path: "setup.sh".to_string(),
+            args: vec!["--all".to_string()],
+            rollback: true,
+            ..Script::default()
+        };
+
+        let explanations = script.explain(&context).unwrap();
+
+        assert_eq!(explanations[0].message, "About to run script \"setup.sh --all\" to roll back");
+    }
+
+    #[test]
+    fn rollback_without_undo_is_skipped() {
+        let dir = given_a_script("setup.sh", "#!/bin/sh\ntouch ran\n", 0o755);
+        let context = Context {
+            working_directory: dir.path().to_path_buf(),
+            direction: Direction::Rollback,
+            ..Context::default()
+        };
+
+        let script = Script { path: "setup.sh".to_string(), ..Script::default() };
+
+        assert_eq!(script.explain(&context).unwrap()[0].message, "No undo for script \"setup.sh\", skipping it");
+        script.rollback(&context).unwrap();
+        assert!(!dir.path().join("ran").exists());
+    }
+
+    #[test]
+    fn rollback_runs_the_undo_script() {
+        let dir = given_a_script("setup.sh", "#!/bin/sh\ntouch installed\n", 0o755);
+        let undo = dir.path().join("teardown.sh");
+        fs::write(&undo, "#!/bin/sh\necho \"$DOTTER_DIRECTION $1\" > out\n").unwrap();
+        fs::set_permissions(&undo, fs::Permissions::from_mode(0o755)).unwrap();
+        let context = Context {
+            working_directory: dir.path().to_path_buf(),
+            direction: Direction::Rollback,
+            ..Context::default()
+        };
+
+        let script = Script {
+            path: "setup.sh".to_string(),
+            args: vec!["--all".to_string()],
+            undo: Some("teardown.sh".to_string()),
+            ..Script::default()
+        };
+
+        assert_eq!(script.explain(&context).unwrap()[0].message, "About to run script \"teardown.sh --all\" to roll back");
+        script.rollback(&context).unwrap();
+        assert!(!dir.path().join("installed").exists());
+        assert_eq!(fs::read_to_string(dir.path().join("out")).unwrap(), "rollback --all\n");
+    }
+
+    #[test]
+    fn explaining_refuses_missing_or_non_executable_scripts() {
+        let dir = given_a_script("setup.sh", "#!/bin/sh\n", 0o644);
+        let context = Context {
+            working_directory: dir.path().to_path_buf(),
+            ..Context::default()
+        };
+
+        let not_executable = Script { path: "setup.sh".to_string(), ..Script::default() };
+        let missing = Script { path: "missing.sh".to_string(), ..Script::default() };
+
+        let error = not_executable.explain(&context).unwrap_err();
+        assert_eq!(error.to_string(), format!("Script {}/setup.sh is not executable", dir.path().display()));
+
+        let error = missing.explain(&context).unwrap_err();
+        assert_eq!(error.to_string(), format!("Script {}/missing.sh does not exist", dir.path().display()));
+    }
+
+    #[test]
+    fn failing_scripts_are_errors() {
+        let dir = given_a_script("broken.sh", "#!/bin/sh\nexit 2\n", 0o755);
+        let context = Context {
+            working_directory: dir.path().to_path_buf(),
+            ..Context::default()
+        };
+
+        let script = Script { path: "broken.sh".to_string(), ..Script::default() };
+
+        let error = script.execute(&context).unwrap_err();
+        assert_eq!(error.to_string(), format!("Script {}/broken.sh failed with exit status: 2", dir.path().display()));
+    }
+}
@@ -0,0 +1,28 @@
+use crate::packages::ProviderKind;
+use crate::symlinks::OnConflict;
+
+/// Inventory-wide configuration, read from the reserved `settings` key next to the groups.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    /// The brew binary to use instead of looking for one, `$VARIABLES` are expanded.
+    #[serde(default)]
+    pub brew_path: Option<String>,
+
+    /// Where to get the Homebrew installer from when brew is missing: a URL, for example of a
+    /// mirror, or a local path.
+    #[serde(default)]
+    pub homebrew_installer: Option<String>,
+
+    /// The sha256 the installer has to have, without it Homebrew is never installed.
+    #[serde(default)]
+    pub homebrew_installer_sha256: Option<String>,
+
+    /// Which provider installs `packages` that do not name one, instead of detecting it.
+    #[serde(default)]
+    pub package_provider: Option<ProviderKind>,
+
+    /// What symlinks do about files already in their way, unless they say otherwise.
+    #[serde(default)]
+    pub on_conflict: Option<OnConflict>,
+}
@@ -1,34 +1,225 @@
-use crate::{Command, Context, Explanation, Result};
-use std::process::{self};
+use crate::process::{retry, Invocation, Output, RetryPolicy};
+use crate::{Command, Context, Direction, Explanation, Result};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fmt;
+use std::process::ExitStatus;
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Default)]
 pub struct ShellCommand {
     run: String,
+
+    /// Run on rollback to revert whatever `run` did.
+    #[serde(default)]
+    undo: Option<String>,
+
+    /// Skip `run` if this path already exists.
+    #[serde(default)]
+    creates: Option<String>,
+
+    /// Skip `run` if this command succeeds.
+    #[serde(default)]
+    unless: Option<String>,
+
+    /// Only `run` if this command succeeds.
+    #[serde(default)]
+    onlyif: Option<String>,
+
+    /// Treat the command as successful no matter how it exits.
+    #[serde(default)]
+    ignore_errors: bool,
+
+    /// Exit codes that count as success, only `0` if empty.
+    #[serde(default)]
+    allowed_exit_codes: Vec<i32>,
+
+    /// Directory to run in, relative to the working directory.
+    #[serde(default)]
+    cwd: Option<String>,
+
+    /// Extra environment variables, their values can refer to `$VARIABLES` themselves.
+    #[serde(default)]
+    env: HashMap<String, String>,
+
+    /// What to run the command with.
+    #[serde(default)]
+    shell: Interpreter,
+
+    /// Timeout and retries for `run` and `undo`.
+    #[serde(flatten)]
+    policy: RetryPolicy,
+
+    /// Whether a guard skipped `run` on the last execute, so `--atomic` does not undo it.
+    #[serde(skip)]
+    skipped: Cell<bool>,
 }
 
-impl Command for ShellCommand {
-    fn execute(&self, _context: &Context) -> Result<()> {
-        process::Command::new("sh")
-            .arg("-c")
-            .arg(&self.run)
-            .status()?;
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Interpreter {
+    #[default]
+    Sh,
+    Bash,
+    Zsh,
+    Fish,
+    Python3,
+}
+
+impl Interpreter {
+    fn program(self) -> &'static str {
+        match self {
+            Interpreter::Sh => "sh",
+            Interpreter::Bash => "bash",
+            Interpreter::Zsh => "zsh",
+            Interpreter::Fish => "fish",
+            Interpreter::Python3 => "python3",
+        }
+    }
+}
 
+#[derive(Fail, Debug)]
+pub struct ShellError {
+    command: String,
+    status: ExitStatus,
+    stderr: String,
+}
+
+impl fmt::Display for ShellError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "\"{}\" failed with {}", self.command, self.status)?;
+        if !self.stderr.is_empty() {
+            write!(f, ":\n{}", self.stderr)?;
+        }
         Ok(())
     }
+}
+
+impl ShellCommand {
+    /// Prepares `script` to run with the interpreter, directory and environment of this step.
+    fn invocation(&self, script: &str, context: &Context) -> Invocation {
+        let cwd = match &self.cwd {
+            Some(cwd) => context.interpolate(cwd),
+            None => context.working_directory.clone(),
+        };
+
+        let mut environment = context.environment.clone();
+        for (key, value) in &self.env {
+            environment.insert(key.clone(), context.expand(value));
+        }
+
+        Invocation::new(self.shell.program())
+            .arg("-c")
+            .arg(script)
+            .cwd(cwd)
+            .environment(environment)
+    }
+
+    /// Evaluates the guards, returning why `run` should be skipped if it should.
+    fn skip_reason(&self, context: &Context) -> Result<Option<String>> {
+        if let Some(creates) = &self.creates {
+            let path = context.interpolate(creates);
+            if path.exists() {
+                return Ok(Some(format!("{} already exists", path.display())));
+            }
+        }
+
+        if let Some(unless) = &self.unless {
+            if self.probe(unless, context)? {
+                return Ok(Some(format!("\"{}\" succeeded", unless)));
+            }
+        }
+
+        if let Some(onlyif) = &self.onlyif {
+            if !self.probe(onlyif, context)? {
+                return Ok(Some(format!("\"{}\" failed", onlyif)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Runs a guard quietly, telling whether it succeeded.
+    fn probe(&self, check: &str, context: &Context) -> Result<bool> {
+        let invocation = self.invocation(check, context).output(Output::Discard);
+
+        Ok(context.runner.run(&invocation)?.status.success())
+    }
+
+    fn run_script(&self, script: &str, context: &Context) -> Result<()> {
+        retry(&self.policy, || self.attempt(script, context))
+    }
+
+    fn attempt(&self, script: &str, context: &Context) -> Result<()> {
+        let invocation = self.invocation(script, context).timeout(self.policy.timeout());
+        let finished = context.runner.run(&invocation)?;
+        if !self.succeeded(finished.status) {
+            return Err(ShellError {
+                command: script.to_string(),
+                status: finished.status,
+                stderr: finished.stderr,
+            }.into());
+        }
 
-    fn rollback(&self, _context: &Context) ->Result<()> {
         Ok(())
     }
 
-    fn explain(&self, _context: &Context) -> Result<Vec<Explanation>> {
-        // do something clever to check if target/source exist
-        Ok(vec![Explanation::new(format!("About to run \"{}\"", self.run))])
+    fn succeeded(&self, status: ExitStatus) -> bool {
+        if self.ignore_errors {
+            return true;
+        }
+
+        match status.code() {
+            Some(code) if self.allowed_exit_codes.is_empty() => code == 0,
+            Some(code) => self.allowed_exit_codes.contains(&code),
+            None => false,
+        }
+    }
+}
+
+impl Command for ShellCommand {
+    fn execute(&self, context: &Context) -> Result<()> {
+        self.skipped.set(self.skip_reason(context)?.is_some());
+        if self.skipped.get() {
+            return Ok(());
+        }
+
+        self.run_script(&self.run, context)
+    }
+
+    fn rollback(&self, context: &Context) -> Result<()> {
+        match &self.undo {
+            Some(undo) => self.run_script(undo, context),
+            None => Ok(()),
+        }
+    }
+
+    fn explain(&self, context: &Context) -> Result<Vec<Explanation>> {
+        let message = match (&context.direction, &self.undo) {
+            (Direction::Execute, _) => match self.skip_reason(context)? {
+                Some(reason) => format!("Will skip \"{}\" because {}", self.run, reason),
+                None => format!("About to run \"{}\"", self.run),
+            },
+            (Direction::Rollback, Some(undo)) => format!("About to undo \"{}\" by running \"{}\"", self.run, undo),
+            (Direction::Rollback, None) => format!("No undo for \"{}\", skipping it", self.run),
+        };
+        Ok(vec![Explanation::new(message)])
+    }
+
+    fn steps(&self) -> Vec<&dyn Command> {
+        vec![self]
+    }
+
+    fn skipped(&self) -> bool {
+        self.skipped.get()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use maplit::hashmap;
+    use std::path::PathBuf;
+    use tempfile::tempdir;
 
     #[test]
     fn it_runs_a_simple_shell_command() {
@@ -36,8 +227,318 @@ mod tests {
 
         let echo_command = ShellCommand {
             run: "echo \"Hi there\"".to_string(),
+            ..ShellCommand::default()
+        };
+
+        echo_command.execute(&context).unwrap();
+    }
+
+    #[test]
+    fn failing_commands_report_their_exit_code_and_stderr() {
+        let context = Context::default();
+
+        let failing = ShellCommand {
+            run: "echo first >&2; echo second >&2; exit 3".to_string(),
+            ..ShellCommand::default()
+        };
+
+        let error = failing.execute(&context).unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "\"echo first >&2; echo second >&2; exit 3\" failed with exit status: 3:\nfirst\nsecond"
+        );
+    }
+
+    #[test]
+    fn only_the_end_of_stderr_is_kept() {
+        let context = Context::default();
+
+        let noisy = ShellCommand {
+            run: "for i in $(seq 1 20); do echo line $i >&2; done; exit 1".to_string(),
+            ..ShellCommand::default()
+        };
+
+        let error = noisy.execute(&context).unwrap_err().to_string();
+
+        assert!(!error.contains("line 10\n"));
+        assert!(error.ends_with("line 11\nline 12\nline 13\nline 14\nline 15\nline 16\nline 17\nline 18\nline 19\nline 20"));
+    }
+
+    #[test]
+    fn some_exit_codes_can_be_allowed() {
+        let context = Context::default();
+
+        let grep = ShellCommand {
+            run: "exit 1".to_string(),
+            allowed_exit_codes: vec![0, 1],
+            ..ShellCommand::default()
+        };
+        let other = ShellCommand {
+            run: "exit 2".to_string(),
+            allowed_exit_codes: vec![0, 1],
+            ..ShellCommand::default()
+        };
+
+        assert!(grep.execute(&context).is_ok());
+        assert_eq!(other.execute(&context).unwrap_err().to_string(), "\"exit 2\" failed with exit status: 2");
+    }
+
+    #[test]
+    fn rollback_runs_the_undo_command() {
+        let dir = tempdir().unwrap();
+        let marker = dir.path().join("marker");
+        let context = Context {
+            direction: Direction::Rollback,
+            ..Context::default()
+        };
+
+        let touch = ShellCommand {
+            run: format!("touch {}", marker.display()),
+            undo: Some(format!("rm {}", marker.display())),
+            ..ShellCommand::default()
+        };
+
+        touch.execute(&context).unwrap();
+        assert!(marker.exists());
+
+        touch.rollback(&context).unwrap();
+        assert!(!marker.exists());
+
+        let explanation = &touch.explain(&context).unwrap()[0];
+        assert_eq!(explanation.message, format!("About to undo \"touch {0}\" by running \"rm {0}\"", marker.display()));
+    }
+
+    #[test]
+    fn rollback_without_undo_is_skipped() {
+        let context = Context {
+            direction: Direction::Rollback,
+            ..Context::default()
+        };
+
+        let command = ShellCommand {
+            run: "exit 1".to_string(),
+            ..ShellCommand::default()
+        };
+
+        assert!(command.rollback(&context).is_ok());
+        assert_eq!(command.explain(&context).unwrap()[0].message, "No undo for \"exit 1\", skipping it");
+    }
+
+    #[test]
+    fn failing_undo_commands_are_errors() {
+        let context = Context::default();
+
+        let command = ShellCommand {
+            run: "true".to_string(),
+            undo: Some("exit 4".to_string()),
+            ..ShellCommand::default()
+        };
+
+        assert_eq!(command.rollback(&context).unwrap_err().to_string(), "\"exit 4\" failed with exit status: 4");
+    }
+
+    #[test]
+    fn creates_skips_when_the_path_exists() {
+        let dir = tempdir().unwrap();
+        let context = Context {
+            working_directory: dir.path().to_path_buf(),
+            ..Context::default()
+        };
+
+        let command = ShellCommand {
+            run: "touch plugged".to_string(),
+            creates: Some("plugged".to_string()),
+            ..ShellCommand::default()
+        };
+
+        assert_eq!(command.explain(&context).unwrap()[0].message, "About to run \"touch plugged\"");
+
+        std::fs::create_dir(dir.path().join("plugged")).unwrap();
+
+        let expected = format!("Will skip \"touch plugged\" because {}/plugged already exists", dir.path().display());
+        assert_eq!(command.explain(&context).unwrap()[0].message, expected);
+    }
+
+    #[test]
+    fn skipped_commands_are_not_undone_by_an_atomic_run() {
+        let dir = tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("plugged")).unwrap();
+        let context = Context {
+            working_directory: dir.path().to_path_buf(),
+            atomic: true,
+            ..Context::default()
+        };
+
+        let guarded = ShellCommand {
+            run: "touch plugged".to_string(),
+            creates: Some("plugged".to_string()),
+            undo: Some("rm -r plugged".to_string()),
+            ..ShellCommand::default()
+        };
+        let failing = ShellCommand {
+            run: "exit 1".to_string(),
+            ..ShellCommand::default()
+        };
+
+        let error = vec![guarded, failing].dispatch(&context).unwrap_err();
+
+        assert!(error.to_string().ends_with("Rolled back 1 completed step(s)"), "{}", error);
+        assert!(dir.path().join("plugged").exists());
+    }
+
+    #[test]
+    fn unless_skips_when_the_probe_succeeds() {
+        let context = Context::default();
+
+        let command = ShellCommand {
+            run: "exit 1".to_string(),
+            unless: Some("true".to_string()),
+            ..ShellCommand::default()
+        };
+
+        assert!(command.execute(&context).is_ok());
+        assert_eq!(command.explain(&context).unwrap()[0].message, "Will skip \"exit 1\" because \"true\" succeeded");
+    }
+
+    #[test]
+    fn onlyif_runs_only_when_the_probe_succeeds() {
+        let context = Context::default();
+
+        let skipped = ShellCommand {
+            run: "exit 1".to_string(),
+            onlyif: Some("false".to_string()),
+            ..ShellCommand::default()
+        };
+        let run = ShellCommand {
+            run: "exit 1".to_string(),
+            onlyif: Some("true".to_string()),
+            ..ShellCommand::default()
+        };
+
+        assert!(skipped.execute(&context).is_ok());
+        assert_eq!(skipped.explain(&context).unwrap()[0].message, "Will skip \"exit 1\" because \"false\" failed");
+        assert!(run.execute(&context).is_err());
+    }
+
+    #[test]
+    fn it_runs_in_the_working_directory_by_default() {
+        let dir = tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("scripts")).unwrap();
+        let context = Context {
+            working_directory: dir.path().to_path_buf(),
+            ..Context::default()
+        };
+
+        let in_working_directory = ShellCommand {
+            run: "touch here".to_string(),
+            ..ShellCommand::default()
+        };
+        let in_scripts = ShellCommand {
+            run: "touch there".to_string(),
+            cwd: Some("scripts".to_string()),
+            ..ShellCommand::default()
+        };
+
+        in_working_directory.execute(&context).unwrap();
+        in_scripts.execute(&context).unwrap();
+
+        assert!(dir.path().join("here").exists());
+        assert!(dir.path().join("scripts/there").exists());
+    }
+
+    #[test]
+    fn it_passes_on_the_context_environment_and_its_own() {
+        let context = Context {
+            environment: hashmap! {
+                "GREETING".to_string() => "hello".to_string(),
+                "PATH".to_string() => std::env::var("PATH").unwrap(),
+            },
+            ..Context::default()
+        };
+
+        let command = ShellCommand {
+            run: "test \"$MESSAGE\" = \"hello world\" && test -z \"$HOME\"".to_string(),
+            env: hashmap! { "MESSAGE".to_string() => "$GREETING world".to_string() },
+            ..ShellCommand::default()
+        };
+
+        command.execute(&context).unwrap();
+    }
+
+    #[test]
+    fn guards_and_commands_go_through_the_runner() {
+        let runner = std::rc::Rc::new(crate::process::fake::FakeRunner::default());
+        let context = Context {
+            runner: runner.clone(),
+            working_directory: PathBuf::from("/dotfiles"),
+            ..Context::default()
+        };
+        runner.respond("bash -c test -d ~/.vim", 1, "");
+
+        let command = ShellCommand {
+            run: "mkdir ~/.vim".to_string(),
+            unless: Some("test -d ~/.vim".to_string()),
+            cwd: Some("vim".to_string()),
+            shell: Interpreter::Bash,
+            ..ShellCommand::default()
+        };
+
+        command.execute(&context).unwrap();
+
+        let invocations = runner.invocations();
+        assert_eq!(runner.commands(), vec!["bash -c test -d ~/.vim", "bash -c mkdir ~/.vim"]);
+        assert_eq!(invocations[0].output, Output::Discard);
+        assert_eq!(invocations[1].cwd, Some(PathBuf::from("/dotfiles/vim")));
+    }
+
+    #[test]
+    fn it_runs_with_a_different_interpreter() {
+        let context = Context::default();
+
+        let command = ShellCommand {
+            run: "[[ -n \"$BASH_VERSION\" ]]".to_string(),
+            shell: Interpreter::Bash,
+            ..ShellCommand::default()
+        };
+
+        command.execute(&context).unwrap();
+    }
+
+    #[test]
+    fn commands_can_time_out_and_be_retried() {
+        let dir = tempdir().unwrap();
+        let context = Context {
+            working_directory: dir.path().to_path_buf(),
+            ..Context::default()
+        };
+
+        let hanging = ShellCommand {
+            run: "echo attempt >> attempts; sleep 10".to_string(),
+            policy: RetryPolicy {
+                timeout_secs: Some(1),
+                retries: 1,
+                retry_delay_secs: 0,
+            },
+            ..ShellCommand::default()
+        };
+
+        let error = hanging.execute(&context).unwrap_err();
+
+        assert_eq!(error.to_string(), "Attempt 2 of 2 failed: \"sh -c echo attempt >> attempts; sleep 10\" timed out after 1 seconds");
+        assert_eq!(std::fs::read_to_string(dir.path().join("attempts")).unwrap(), "attempt\nattempt\n");
+    }
+
+    #[test]
+    fn errors_can_be_ignored() {
+        let context = Context::default();
+
+        let failing = ShellCommand {
+            run: "exit 1".to_string(),
+            ignore_errors: true,
+            ..ShellCommand::default()
         };
 
-        echo_command.execute(&context);
+        assert!(failing.execute(&context).is_ok());
     }
 }
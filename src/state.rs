@@ -0,0 +1,259 @@
+use crate::Result;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use tempfile::NamedTempFile;
+
+/// What dotter changed on this machine, so a rollback only undoes its own work.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct State {
+    #[serde(default)]
+    symlinks: Vec<LinkRecord>,
+
+    #[serde(default)]
+    packages: Vec<PackageRecord>,
+
+    /// The inventory file this run applies, which is recorded on everything it touches.
+    #[serde(skip)]
+    inventory: Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct LinkRecord {
+    pub from: PathBuf,
+    pub to: PathBuf,
+    /// Where whatever was at `to` before was moved to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backup: Option<PathBuf>,
+    /// The inventories that applied it, so pruning one of them leaves what the others use alone.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub inventories: Vec<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct PackageRecord {
+    pub manager: String,
+    pub name: String,
+    /// `false` if the package was already there before dotter got to it.
+    pub installed_by_dotter: bool,
+    /// The inventories that applied it, so pruning one of them leaves what the others use alone.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub inventories: Vec<PathBuf>,
+}
+
+/// Something a step in the inventory puts onto the machine, matched against the state when pruning.
+#[derive(Debug, PartialEq)]
+pub enum Resource {
+    Link(PathBuf),
+    Package(String, String),
+}
+
+impl State {
+    /// Where the state lives unless told otherwise: `$XDG_STATE_HOME/dotter/state.json`,
+    /// falling back to `~/.local/state/dotter/state.json`.
+    pub fn default_location(environment: &HashMap<String, String>) -> Option<PathBuf> {
+        let base = match environment.get("XDG_STATE_HOME") {
+            Some(state_home) if !state_home.is_empty() => PathBuf::from(state_home),
+            _ => PathBuf::from(environment.get("HOME")?).join(".local/state"),
+        };
+        Some(base.join("dotter/state.json"))
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<State> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(State::default());
+        }
+
+        let state = serde_json::from_reader(File::open(path)?)
+            .map_err(|e| format_err!("Could not read state from {}: {}", path.display(), e))?;
+        Ok(state)
+    }
+
+    /// Writes the state next to `path` first and then moves it over, so a failed write never
+    /// leaves a truncated journal behind.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        fs::create_dir_all(parent)?;
+
+        let mut file = NamedTempFile::new_in(parent)?;
+        serde_json::to_writer_pretty(&mut file, self)?;
+        file.as_file().sync_all()?;
+        file.persist(path)?;
+        Ok(())
+    }
+
+    /// Records what happens from now on as applied by the inventory at `inventory`.
+    pub fn applied_by(self, inventory: PathBuf) -> State {
+        State { inventory: Some(inventory), ..self }
+    }
+
+    pub fn inventory(&self) -> Option<&Path> {
+        self.inventory.as_deref()
+    }
+
+    pub fn links(&self) -> &[LinkRecord] {
+        &self.symlinks
+    }
+
+    pub fn packages(&self) -> &[PackageRecord] {
+        &self.packages
+    }
+
+    pub fn created_link(&mut self, from: &Path, to: &Path) {
+        let inventories = self.link(to).map(|link| link.inventories.clone()).unwrap_or_default();
+        self.removed_link(to);
+        self.symlinks.push(LinkRecord {
+            from: from.to_path_buf(),
+            to: to.to_path_buf(),
+            backup: None,
+            inventories: self.claimed(inventories),
+        });
+    }
+
+    /// Remembers that what was at `to` before the link was moved to `backup`.
+    pub fn backed_up(&mut self, to: &Path, backup: &Path) {
+        if let Some(link) = self.symlinks.iter_mut().find(|link| link.to == to) {
+            link.backup = Some(backup.to_path_buf());
+        }
+    }
+
+    pub fn link(&self, to: &Path) -> Option<&LinkRecord> {
+        self.symlinks.iter().find(|link| link.to == to)
+    }
+
+    pub fn removed_link(&mut self, to: &Path) {
+        self.symlinks.retain(|link| link.to != to);
+    }
+
+    /// Forgets that the current inventory applied the link at `to`, while others still use it.
+    pub fn disowned_link(&mut self, to: &Path) {
+        let inventory = self.inventory.clone();
+        if let Some(link) = self.symlinks.iter_mut().find(|link| link.to == to) {
+            link.inventories.retain(|applied_by| Some(applied_by) != inventory.as_ref());
+        }
+    }
+
+    pub fn installed_package(&mut self, manager: &str, name: &str) {
+        self.record_package(manager, name, true);
+    }
+
+    /// Remembers a package that was already installed, unless dotter installed it on an earlier run.
+    pub fn found_package(&mut self, manager: &str, name: &str) {
+        let installed_by_dotter = self.installed_by_dotter(manager, name);
+        self.record_package(manager, name, installed_by_dotter);
+    }
+
+    pub fn installed_by_dotter(&self, manager: &str, name: &str) -> bool {
+        self.package(manager, name).map(|package| package.installed_by_dotter).unwrap_or(false)
+    }
+
+    pub fn removed_package(&mut self, manager: &str, name: &str) {
+        self.packages.retain(|package| package.manager != manager || package.name != name);
+    }
+
+    /// Forgets that the current inventory applied the package, while others still use it.
+    pub fn disowned_package(&mut self, manager: &str, name: &str) {
+        let inventory = self.inventory.clone();
+        if let Some(package) = self.packages.iter_mut().find(|package| package.manager == manager && package.name == name) {
+            package.inventories.retain(|applied_by| Some(applied_by) != inventory.as_ref());
+        }
+    }
+
+    fn package(&self, manager: &str, name: &str) -> Option<&PackageRecord> {
+        self.packages.iter().find(|package| package.manager == manager && package.name == name)
+    }
+
+    fn record_package(&mut self, manager: &str, name: &str, installed_by_dotter: bool) {
+        let inventories = self.package(manager, name).map(|package| package.inventories.clone()).unwrap_or_default();
+        self.removed_package(manager, name);
+        self.packages.push(PackageRecord {
+            manager: manager.to_string(),
+            name: name.to_string(),
+            installed_by_dotter,
+            inventories: self.claimed(inventories),
+        });
+    }
+
+    /// `inventories` with the current inventory added to them.
+    fn claimed(&self, mut inventories: Vec<PathBuf>) -> Vec<PathBuf> {
+        if let Some(inventory) = &self.inventory {
+            if !inventories.contains(inventory) {
+                inventories.push(inventory.clone());
+            }
+        }
+        inventories
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maplit::hashmap;
+    use tempfile::tempdir;
+
+    #[test]
+    fn it_survives_a_round_trip_through_the_file_system() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("nested/state.json");
+
+        let mut state = State::default();
+        state.created_link(Path::new("/dotfiles/vimrc"), Path::new("/home/me/.vimrc"));
+        state.installed_package("brew", "nvim");
+        state.found_package("brew", "git");
+        state.save(&path).unwrap();
+
+        assert_eq!(State::load(&path).unwrap(), state);
+    }
+
+    #[test]
+    fn saving_replaces_the_journal_without_leaving_files_behind() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        fs::write(&path, "{\"packages\": [{\"manager\": \"brew\", \"name\": \"htop\", \"installed_by_dotter\": true}]}").unwrap();
+
+        let mut state = State::default();
+        state.installed_package("brew", "nvim");
+        state.save(&path).unwrap();
+
+        assert_eq!(State::load(&path).unwrap(), state);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn a_missing_file_is_an_empty_state() {
+        let dir = tempdir().unwrap();
+
+        let state = State::load(dir.path().join("state.json")).unwrap();
+
+        assert_eq!(state, State::default());
+    }
+
+    #[test]
+    fn packages_found_later_stay_installed_by_dotter() {
+        let mut state = State::default();
+        state.installed_package("brew", "nvim");
+        state.found_package("brew", "nvim");
+        state.found_package("brew", "git");
+
+        assert!(state.installed_by_dotter("brew", "nvim"));
+        assert!(!state.installed_by_dotter("brew", "git"));
+        assert!(!state.installed_by_dotter("brew", "htop"));
+    }
+
+    #[test]
+    fn it_lives_in_the_xdg_state_directory() {
+        let xdg = hashmap! {
+            "XDG_STATE_HOME".to_string() => "/xdg".to_string(),
+            "HOME".to_string() => "/home/me".to_string(),
+        };
+        let home = hashmap! { "HOME".to_string() => "/home/me".to_string() };
+
+        assert_eq!(State::default_location(&xdg), Some(PathBuf::from("/xdg/dotter/state.json")));
+        assert_eq!(State::default_location(&home), Some(PathBuf::from("/home/me/.local/state/dotter/state.json")));
+    }
+}
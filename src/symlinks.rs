@@ -1,70 +1,245 @@
+use crate::state::Resource;
 use crate::{Command, Context, Direction, Explanation, Result};
-use symlink::{remove_symlink_file, symlink_file};
-use std::path::PathBuf;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use symlink::{remove_symlink_dir, remove_symlink_file, symlink_dir, symlink_file};
+
+/// What to do when something other than the link is already where the link should go.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OnConflict {
+    /// Stop the run with an error.
+    #[default]
+    Fail,
+    /// Move it to `<name>.dotter-backup-<timestamp>`, rollback moves it back.
+    Backup,
+    /// Delete it.
+    Overwrite,
+    /// Leave it and don't create the link.
+    Skip,
+}
+
+#[derive(Fail, Debug)]
+pub enum LinkError {
+    #[fail(display = "{} already exists, set on_conflict to backup, overwrite or skip to link over it", _0)]
+    Conflict(String),
+    #[fail(display = "Can not link to {}, it does not exist", _0)]
+    MissingSource(String),
+}
 
 #[derive(Deserialize, Debug)]
 pub struct Symlink {
     from: String,
     to: String,
+
+    /// Overrides the `on_conflict` setting for this link.
+    #[serde(default)]
+    on_conflict: Option<OnConflict>,
+}
+
+impl Symlink {
+    fn on_conflict(&self, context: &Context) -> OnConflict {
+        self.on_conflict.or(context.settings.on_conflict).unwrap_or_default()
+    }
+
+    /// The file or directory the link points to, which has to exist.
+    fn source(&self, context: &Context) -> Result<PathBuf> {
+        let source = context.working_directory.join(&self.from);
+        if !source.exists() {
+            return Err(LinkError::MissingSource(source.display().to_string()).into());
+        }
+        Ok(source)
+    }
+}
+
+/// Whether `destination` is already a link to `source`.
+fn links_to(destination: &Path, source: &Path) -> bool {
+    fs::read_link(destination).map(|target| target == source).unwrap_or(false)
+}
+
+/// Where `destination` is moved to when it is backed up, numbered so an earlier backup is never overwritten.
+fn backup_path(destination: &Path) -> PathBuf {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or_default();
+    let base = destination.file_name().map(OsString::from).unwrap_or_default();
+
+    (0..)
+        .map(|count| {
+            let mut name = base.clone();
+            match count {
+                0 => name.push(format!(".dotter-backup-{}", timestamp)),
+                _ => name.push(format!(".dotter-backup-{}-{}", timestamp, count)),
+            }
+            destination.with_file_name(name)
+        })
+        .find(|path| path.symlink_metadata().is_err())
+        .expect("one of infinitely many names is free")
+}
+
+/// Deletes whatever is at `path` without following it if it is a link.
+fn remove(path: &Path) -> Result<()> {
+    if path.symlink_metadata()?.is_dir() {
+        fs::remove_dir_all(path)?;
+    } else {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Links to directories need different calls than links to files on Windows.
+fn link(source: &Path, destination: &Path) -> Result<()> {
+    if source.is_dir() {
+        symlink_dir(source, destination)?;
+    } else {
+        symlink_file(source, destination)?;
+    }
+    Ok(())
+}
+
+fn is_link(path: &Path) -> bool {
+    path.symlink_metadata().map(|metadata| metadata.file_type().is_symlink()).unwrap_or(false)
+}
+
+/// Whether the link at `path` was created as a directory link, even if what it points to is gone.
+#[cfg(windows)]
+fn is_directory_link(path: &Path) -> bool {
+    use std::os::windows::fs::FileTypeExt;
+    path.symlink_metadata().map(|metadata| metadata.file_type().is_symlink_dir()).unwrap_or(false)
+}
+
+/// Outside of Windows both kinds of link are removed the same way, so what it points to will do.
+#[cfg(not(windows))]
+fn is_directory_link(path: &Path) -> bool {
+    path.is_dir()
+}
+
+/// Removes a link dotter created, moving what it backed up in its place back. The backup is only
+/// restored if nothing else took the link's place since.
+pub fn unlink(destination: &Path, backup: Option<&Path>) -> Result<()> {
+    let removed = is_link(destination);
+    if removed {
+        if is_directory_link(destination) {
+            remove_symlink_dir(destination)?;
+        } else {
+            remove_symlink_file(destination)?;
+        }
+    }
+
+    if let Some(backup) = backup.filter(|backup| backup.symlink_metadata().is_ok()) {
+        if removed || destination.symlink_metadata().is_err() {
+            fs::rename(backup, destination)?;
+        } else {
+            eprintln!("{} is no longer a link from dotter, leaving its backup in {}", destination.display(), backup.display());
+        }
+    }
+    Ok(())
 }
 
 impl Command for Symlink {
     fn execute(&self, context: &Context) -> Result<()> {
-        let destination = interpolate(&self.to, context);
+        let destination = context.interpolate(&self.to);
 
         let parent = destination.parent().unwrap();
         std::fs::create_dir_all(parent).expect("Trying to create parents");
 
-        symlink_file(context.working_directory.join(&self.from), context.working_directory.join(&destination))?;
+        let source = self.source(context)?;
+        let destination = context.working_directory.join(&destination);
+
+        let mut backup = None;
+        let previous = context.state.borrow().link(&destination).cloned();
+        if destination.symlink_metadata().is_ok() {
+            if links_to(&destination, &source) {
+                return Ok(());
+            }
+
+            match self.on_conflict(context) {
+                // An earlier link of dotter's that now has to point elsewhere, keeping its backup.
+                _ if previous.is_some() && is_link(&destination) => {
+                    unlink(&destination, None)?;
+                    backup = previous.and_then(|link| link.backup);
+                }
+                OnConflict::Fail => return Err(LinkError::Conflict(destination.display().to_string()).into()),
+                OnConflict::Skip => return Ok(()),
+                OnConflict::Overwrite => remove(&destination)?,
+                OnConflict::Backup => {
+                    let path = backup_path(&destination);
+                    fs::rename(&destination, &path)?;
+                    backup = Some(path);
+                }
+            }
+        }
+
+        link(&source, &destination)?;
+        let mut state = context.state.borrow_mut();
+        state.created_link(&source, &destination);
+        if let Some(backup) = backup {
+            state.backed_up(&destination, &backup);
+        }
         Ok(())
     }
 
     fn rollback(&self, context: &Context) -> Result<()> {
-        let destination = interpolate(&self.to, context);
+        let destination = context.working_directory.join(context.interpolate(&self.to));
+
+        let backup = match context.state.borrow().link(&destination) {
+            Some(link) => link.backup.clone(),
+            None => return Ok(()),
+        };
 
-        remove_symlink_file(context.working_directory.join(&destination))?;
+        unlink(&destination, backup.as_deref())?;
+        context.state.borrow_mut().removed_link(&destination);
         Ok(())
     }
 
     fn explain(&self, context: &Context) -> Result<Vec<Explanation>> {
-        let destination = interpolate(&self.to, context);
+        let destination = context.working_directory.join(context.interpolate(&self.to));
+        let link = context.state.borrow().link(&destination).cloned();
         let message = match context.direction {
             Direction::Execute => {
-                if destination.exists() {
+                let source = self.source(context)?;
+                if links_to(&destination, &source) {
                     format!("Symmlink to {} already exists", destination.display())
+                } else if link.is_some() && is_link(&destination) {
+                    format!("Pointing symmlink {} at {} instead", destination.display(), self.from)
+                } else if destination.symlink_metadata().is_ok() {
+                    match self.on_conflict(context) {
+                        OnConflict::Fail => format!("{} already exists, linking to it will fail", destination.display()),
+                        OnConflict::Skip => format!("{} already exists, skipping it", destination.display()),
+                        OnConflict::Overwrite => format!("Replacing {} with a link from {}", destination.display(), self.from),
+                        OnConflict::Backup => format!("Backing up {} and adding a link from {}", destination.display(), self.from),
+                    }
                 } else {
                     format!("adding a link from {} to {}", self.from, destination.display())
                 }
             },
             Direction::Rollback => {
-                if destination.exists() {
+                let backup = link.as_ref().and_then(|link| link.backup.as_ref());
+                if let Some(backup) = backup {
+                    format!("Removing symmlink to {} and restoring {}", destination.display(), backup.display())
+                } else if destination.symlink_metadata().is_err() {
+                    format!("Symmlink to {} did not exist", destination.display())
+                } else if link.is_some() {
                     format!("Removing symmlink to {}", destination.display())
                 } else {
-                    format!("Symmlink to {} did not exist", destination.display())
+                    format!("Symmlink to {} was not created by dotter, leaving it alone", destination.display())
                 }
             },
         };
 
         Ok(vec![Explanation::new(message)])
     }
-}
-
 
-fn interpolate(target: &str, context: &Context) -> PathBuf {
-    if !target.contains("$") {
-        return context.working_directory.join(target)
+    fn steps(&self) -> Vec<&dyn Command> {
+        vec![self]
     }
 
-    let mut better_target = target.to_string();
-    for (key, value) in context.environment.iter() {
-        if  target.contains(key) {
-            let x = format!("${}", key);
-            better_target = better_target.replace(&x, &value);
-        }
+    fn resources(&self, context: &Context) -> Vec<Resource> {
+        vec![Resource::Link(context.working_directory.join(context.interpolate(&self.to)))]
     }
-    context.working_directory.join(better_target)
 }
 
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -100,6 +275,7 @@ mod tests {
         let linker = Symlink {
             from: "original.txt".to_string(),
             to: "the_copy.txt".to_string(),
+            on_conflict: None,
         };
 
         let context = Context {
@@ -107,12 +283,12 @@ mod tests {
             ..Context::default()
         };
 
-        linker.execute(&context);
+        linker.execute(&context).unwrap();
 
         let paths = std::fs::read_dir(&context.working_directory).unwrap();
         assert_eq!(paths.count(), 2);
 
-        linker.rollback(&context);
+        linker.rollback(&context).unwrap();
 
         let after = std::fs::read_dir(&context.working_directory).unwrap();
         assert_eq!(after.count(), 1);
@@ -126,6 +302,7 @@ mod tests {
         let linker = Symlink {
             from: "original.txt".to_string(),
             to: "$SOME_ENV_FLAG/the_copy.txt".to_string(),
+            on_conflict: None,
         };
 
         let environment = hashmap! {
@@ -134,28 +311,30 @@ mod tests {
 
         let context = Context {
             working_directory: dir.into_path(),
-            environment: environment,
+            environment,
             ..Context::default()
         };
 
-        linker.execute(&context);
+        linker.execute(&context).unwrap();
 
-        let paths = std::fs::read_dir(&context.working_directory.join("fancy_subdir")).unwrap();
+        let paths = std::fs::read_dir(context.working_directory.join("fancy_subdir")).unwrap();
         assert_eq!(paths.count(), 1);
 
-        linker.rollback(&context);
+        linker.rollback(&context).unwrap();
 
-        let after = std::fs::read_dir(&context.working_directory.join("fancy_subdir")).unwrap();
+        let after = std::fs::read_dir(context.working_directory.join("fancy_subdir")).unwrap();
         assert_eq!(after.count(), 0);
     }
 
     #[test]
     fn it_will_inform_about_unnecessary_links() {
-        let dir = given_these_files_exist(&["original.txt", "the_copy.txt"]);
+        let dir = given_a_file_exists("original.txt");
+        symlink_file(dir.path().join("original.txt"), dir.path().join("the_copy.txt")).unwrap();
 
         let linker = Symlink {
             from: "original.txt".to_string(),
             to: "the_copy.txt".to_string(),
+            on_conflict: None,
         };
 
         let context = Context {
@@ -167,7 +346,249 @@ mod tests {
 
         let expected = format!("Symmlink to {}/the_copy.txt already exists", context.working_directory.display());
 
-        assert_eq!(explanations.get(0).unwrap().message, expected);
+        assert_eq!(explanations.first().unwrap().message, expected);
+    }
+
+    fn conflicting(on_conflict: Option<OnConflict>) -> (Symlink, Context) {
+        let dir = given_these_files_exist(&["original.txt", "the_copy.txt"]);
+        let linker = Symlink {
+            from: "original.txt".to_string(),
+            to: "the_copy.txt".to_string(),
+            on_conflict,
+        };
+        let context = Context {
+            working_directory: dir.into_path(),
+            ..Context::default()
+        };
+        (linker, context)
+    }
+
+    #[test]
+    fn conflicting_files_fail_the_link_by_default() {
+        let (linker, context) = conflicting(None);
+
+        let error = linker.execute(&context).unwrap_err();
+
+        let expected = format!("{}/the_copy.txt already exists, set on_conflict to backup, overwrite or skip to link over it", context.working_directory.display());
+        assert_eq!(error.to_string(), expected);
+        assert!(context.state.borrow().link(&context.working_directory.join("the_copy.txt")).is_none());
+    }
+
+    #[test]
+    fn conflicting_files_can_be_skipped_or_overwritten() {
+        let (linker, context) = conflicting(Some(OnConflict::Skip));
+        linker.execute(&context).unwrap();
+        assert!(!is_link(&context.working_directory.join("the_copy.txt")));
+
+        let (linker, context) = conflicting(Some(OnConflict::Overwrite));
+        linker.execute(&context).unwrap();
+        assert!(is_link(&context.working_directory.join("the_copy.txt")));
+        assert_eq!(std::fs::read_dir(&context.working_directory).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn backups_are_not_restored_over_files_that_replaced_the_link() {
+        let (linker, context) = conflicting(Some(OnConflict::Backup));
+        let destination = context.working_directory.join("the_copy.txt");
+        linker.execute(&context).unwrap();
+        let backup = context.state.borrow().link(&destination).unwrap().backup.clone().unwrap();
+
+        std::fs::remove_file(&destination).unwrap();
+        std::fs::write(&destination, "written since").unwrap();
+        let context = Context { direction: Direction::Rollback, ..context };
+        linker.rollback(&context).unwrap();
+
+        assert_eq!(std::fs::read_to_string(&destination).unwrap(), "written since");
+        assert!(backup.exists());
+    }
+
+    #[test]
+    fn moving_a_link_keeps_its_backup() {
+        let (linker, context) = conflicting(Some(OnConflict::Backup));
+        let destination = context.working_directory.join("the_copy.txt");
+        std::fs::write(&destination, "my own config").unwrap();
+        std::fs::write(context.working_directory.join("moved.txt"), "moved").unwrap();
+        linker.execute(&context).unwrap();
+        let backup = context.state.borrow().link(&destination).unwrap().backup.clone();
+
+        let moved = Symlink {
+            from: "moved.txt".to_string(),
+            to: "the_copy.txt".to_string(),
+            on_conflict: None,
+        };
+        let explanations = moved.explain(&context).unwrap();
+        assert_eq!(explanations[0].message, format!("Pointing symmlink {} at moved.txt instead", destination.display()));
+        moved.execute(&context).unwrap();
+
+        assert_eq!(std::fs::read_to_string(&destination).unwrap(), "moved");
+        assert_eq!(context.state.borrow().link(&destination).unwrap().backup, backup);
+
+        let context = Context { direction: Direction::Rollback, ..context };
+        moved.rollback(&context).unwrap();
+        assert_eq!(std::fs::read_to_string(&destination).unwrap(), "my own config");
+    }
+
+    #[test]
+    fn dangling_links_from_dotter_are_still_removed_on_rollback() {
+        let dir = given_a_file_exists("original.txt");
+        let linker = Symlink {
+            from: "original.txt".to_string(),
+            to: "the_copy.txt".to_string(),
+            on_conflict: None,
+        };
+        let context = Context {
+            working_directory: dir.into_path(),
+            ..Context::default()
+        };
+        linker.execute(&context).unwrap();
+        std::fs::remove_file(context.working_directory.join("original.txt")).unwrap();
+
+        let context = Context { direction: Direction::Rollback, ..context };
+        let explanations = linker.explain(&context).unwrap();
+        let expected = format!("Removing symmlink to {}/the_copy.txt", context.working_directory.display());
+        assert_eq!(explanations[0].message, expected);
+
+        linker.rollback(&context).unwrap();
+        assert_eq!(std::fs::read_dir(&context.working_directory).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn backups_never_overwrite_earlier_backups() {
+        let dir = given_a_file_exists("config");
+        let destination = dir.path().join("config");
+
+        let first = backup_path(&destination);
+        std::fs::write(&first, "first backup").unwrap();
+        let second = backup_path(&destination);
+
+        assert_ne!(first, second);
+        assert!(!second.exists());
+        assert_eq!(std::fs::read_to_string(&first).unwrap(), "first backup");
+    }
+
+    #[test]
+    fn backups_are_restored_on_rollback() {
+        let (linker, mut context) = conflicting(None);
+        context.settings.on_conflict = Some(OnConflict::Backup);
+        let destination = context.working_directory.join("the_copy.txt");
+        std::fs::write(&destination, "my own config").unwrap();
+
+        linker.execute(&context).unwrap();
+
+        assert!(is_link(&destination));
+        let backup = context.state.borrow().link(&destination).unwrap().backup.clone().unwrap();
+        assert!(backup.file_name().unwrap().to_string_lossy().starts_with("the_copy.txt.dotter-backup-"));
+        assert_eq!(std::fs::read_to_string(&backup).unwrap(), "my own config");
+
+        let context = Context { direction: Direction::Rollback, ..context };
+        let explanations = linker.explain(&context).unwrap();
+        let expected = format!("Removing symmlink to {} and restoring {}", destination.display(), backup.display());
+        assert_eq!(explanations.first().unwrap().message, expected);
+
+        linker.rollback(&context).unwrap();
+
+        assert_eq!(std::fs::read_to_string(&destination).unwrap(), "my own config");
+        assert!(!backup.exists());
+    }
+
+    #[test]
+    fn it_links_directories() {
+        let dir = tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("nvim/plugged")).unwrap();
+        std::fs::write(dir.path().join("nvim/plugged/plug.vim"), "\" plugins").unwrap();
+
+        let linker = Symlink {
+            from: "nvim/plugged".to_string(),
+            to: "config/plugged".to_string(),
+            on_conflict: None,
+        };
+        let context = Context {
+            working_directory: dir.into_path(),
+            ..Context::default()
+        };
+
+        linker.execute(&context).unwrap();
+
+        let destination = context.working_directory.join("config/plugged");
+        assert!(is_link(&destination));
+        assert!(destination.join("plug.vim").exists());
+
+        linker.rollback(&context).unwrap();
+
+        assert!(destination.symlink_metadata().is_err());
+        assert!(context.working_directory.join("nvim/plugged/plug.vim").exists());
+    }
+
+    #[test]
+    fn dangling_directory_links_are_removed() {
+        let dir = tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("autoload")).unwrap();
+
+        let linker = Symlink {
+            from: "autoload".to_string(),
+            to: "linked".to_string(),
+            on_conflict: None,
+        };
+        let context = Context {
+            working_directory: dir.into_path(),
+            ..Context::default()
+        };
+
+        linker.execute(&context).unwrap();
+        std::fs::remove_dir(context.working_directory.join("autoload")).unwrap();
+
+        let context = Context { direction: Direction::Rollback, ..context };
+        linker.rollback(&context).unwrap();
+
+        assert!(context.working_directory.join("linked").symlink_metadata().is_err());
+        assert!(context.state.borrow().link(&context.working_directory.join("linked")).is_none());
+    }
+
+    #[test]
+    fn it_refuses_to_explain_links_to_nothing() {
+        let dir = tempdir().unwrap();
+
+        let linker = Symlink {
+            from: "nvim/autoload".to_string(),
+            to: "autoload".to_string(),
+            on_conflict: None,
+        };
+        let context = Context {
+            working_directory: dir.into_path(),
+            ..Context::default()
+        };
+
+        let error = linker.explain(&context).unwrap_err();
+
+        let expected = format!("Can not link to {}/nvim/autoload, it does not exist", context.working_directory.display());
+        assert_eq!(error.to_string(), expected);
+    }
+
+    #[test]
+    fn it_leaves_links_alone_that_it_did_not_create() {
+        let dir = given_a_file_exists("original.txt");
+        symlink_file(dir.path().join("original.txt"), dir.path().join("the_copy.txt")).unwrap();
+
+        let linker = Symlink {
+            from: "original.txt".to_string(),
+            to: "the_copy.txt".to_string(),
+            on_conflict: None,
+        };
+
+        let context = Context {
+            working_directory: dir.into_path(),
+            direction: Direction::Rollback,
+            ..Context::default()
+        };
+
+        let explanations = linker.explain(&context).unwrap();
+        let expected = format!("Symmlink to {}/the_copy.txt was not created by dotter, leaving it alone", context.working_directory.display());
+        assert_eq!(explanations.first().unwrap().message, expected);
+
+        linker.rollback(&context).unwrap();
+
+        let after = std::fs::read_dir(&context.working_directory).unwrap();
+        assert_eq!(after.count(), 2);
     }
 
     #[test]
@@ -177,6 +598,7 @@ mod tests {
         let linker = Symlink {
             from: "original.txt".to_string(),
             to: "the_copy.txt".to_string(),
+            on_conflict: None,
         };
 
         let context = Context {
@@ -189,6 +611,6 @@ mod tests {
 
         let expected = format!("Symmlink to {}/the_copy.txt did not exist", context.working_directory.display());
 
-        assert_eq!(explanations.get(0).unwrap().message, expected);
+        assert_eq!(explanations.first().unwrap().message, expected);
     }
 }
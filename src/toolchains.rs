@@ -0,0 +1,281 @@
+use crate::packages::{self, capture, run, Packages, Provider, ProviderKind};
+use crate::process::Invocation;
+use crate::{Context, Result};
+use serde::Deserializer;
+use serde_json::Value;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// Binaries installed with `cargo install`.
+pub struct Cargo;
+
+impl Provider for Cargo {
+    fn name(&self) -> &'static str {
+        "cargo"
+    }
+
+    /// `cargo install --list` prints `ripgrep v13.0.0:` for every crate, followed by its
+    /// binaries indented underneath.
+    fn installed(&self, context: &Context) -> Result<HashSet<String>> {
+        let listing = capture(Invocation::new("cargo").arg("install").arg("--list"), context)?;
+
+        Ok(listing
+            .lines()
+            .filter(|line| !line.starts_with(char::is_whitespace))
+            .filter_map(|line| line.split_whitespace().next())
+            .map(String::from)
+            .collect())
+    }
+
+    fn install(&self, names: &[String], context: &Context) -> Result<()> {
+        run(&Invocation::new("cargo").arg("install").args(names), context).map(|_| ())
+    }
+
+    fn remove(&self, name: &str, context: &Context) -> Result<()> {
+        run(&Invocation::new("cargo").arg("uninstall").arg(name), context).map(|_| ())
+    }
+}
+
+/// Global npm packages.
+pub struct Npm;
+
+impl Provider for Npm {
+    fn name(&self) -> &'static str {
+        "npm"
+    }
+
+    fn installed(&self, context: &Context) -> Result<HashSet<String>> {
+        let listing = capture(Invocation::new("npm").arg("ls").arg("-g").arg("--depth=0").arg("--json"), context)?;
+        keys(&listing, "dependencies")
+    }
+
+    fn install(&self, names: &[String], context: &Context) -> Result<()> {
+        run(&Invocation::new("npm").arg("install").arg("-g").args(names), context).map(|_| ())
+    }
+
+    fn remove(&self, name: &str, context: &Context) -> Result<()> {
+        run(&Invocation::new("npm").arg("uninstall").arg("-g").arg(name), context).map(|_| ())
+    }
+}
+
+/// Python applications installed with pipx, each into its own virtualenv.
+pub struct Pipx;
+
+impl Provider for Pipx {
+    fn name(&self) -> &'static str {
+        "pipx"
+    }
+
+    fn installed(&self, context: &Context) -> Result<HashSet<String>> {
+        let listing = capture(Invocation::new("pipx").arg("list").arg("--json"), context)?;
+        keys(&listing, "venvs")
+    }
+
+    fn install(&self, names: &[String], context: &Context) -> Result<()> {
+        run(&Invocation::new("pipx").arg("install").args(names), context).map(|_| ())
+    }
+
+    fn remove(&self, name: &str, context: &Context) -> Result<()> {
+        run(&Invocation::new("pipx").arg("uninstall").arg(name), context).map(|_| ())
+    }
+}
+
+/// The keys of the `field` object in the JSON `listing`.
+fn keys(listing: &str, field: &str) -> Result<HashSet<String>> {
+    let listing: Value = serde_json::from_str(listing)?;
+
+    Ok(listing
+        .get(field)
+        .and_then(Value::as_object)
+        .map(|packages| packages.keys().cloned().collect())
+        .unwrap_or_default())
+}
+
+/// Go binaries, written as the package path like `golang.org/x/tools/gopls@latest`.
+pub struct Go;
+
+impl Go {
+    /// Where `go install` puts binaries: `GOBIN`, or else the `bin` directory of the first `GOPATH`.
+    fn bin(&self, context: &Context) -> Result<PathBuf> {
+        let listing = capture(Invocation::new("go").arg("env").arg("GOBIN").arg("GOPATH"), context)?;
+        let mut lines = listing.lines();
+        let gobin = lines.next().unwrap_or_default().trim();
+        let gopath = lines.next().unwrap_or_default().trim();
+
+        match (gobin, std::env::split_paths(gopath).next()) {
+            (gobin, _) if !gobin.is_empty() => Ok(PathBuf::from(gobin)),
+            (_, Some(gopath)) if !gopath.as_os_str().is_empty() => Ok(gopath.join("bin")),
+            _ => Err(format_err!("Could not tell where go installs binaries, neither GOBIN nor GOPATH is set")),
+        }
+    }
+}
+
+/// The binary `go install` builds for `package`: the last element of its path, without the
+/// version and any `/v2` style major version suffix.
+pub fn binary(package: &str) -> &str {
+    let path = package.split('@').next().unwrap_or(package).trim_end_matches('/');
+    let mut elements = path.rsplit('/');
+    let last = elements.next().unwrap_or(path);
+
+    let is_major_version = last.len() > 1 && last.starts_with('v') && last[1..].chars().all(|c| c.is_ascii_digit());
+    match elements.next() {
+        Some(previous) if is_major_version => previous,
+        _ => last,
+    }
+}
+
+impl Provider for Go {
+    fn name(&self) -> &'static str {
+        "go"
+    }
+
+    fn installed(&self, context: &Context) -> Result<HashSet<String>> {
+        let bin = self.bin(context)?;
+        let entries = match fs::read_dir(&bin) {
+            Ok(entries) => entries,
+            Err(_) => return Ok(HashSet::new()),
+        };
+
+        Ok(entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .collect())
+    }
+
+    fn installed_as<'a>(&self, name: &'a str) -> &'a str {
+        binary(name)
+    }
+
+    /// Installs one package at a time, `go install` only takes several versioned packages from
+    /// the same module.
+    fn install(&self, names: &[String], context: &Context) -> Result<()> {
+        for name in names {
+            let package = if name.contains('@') { name.clone() } else { format!("{}@latest", name) };
+            run(&Invocation::new("go").arg("install").arg(package), context)?;
+        }
+        Ok(())
+    }
+
+    fn remove(&self, name: &str, context: &Context) -> Result<()> {
+        let binary = self.bin(context)?.join(binary(name));
+        match fs::remove_file(&binary) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => Err(format_err!("Could not remove {}: {}", binary.display(), error)),
+            _ => Ok(()),
+        }
+    }
+}
+
+pub fn cargo<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Packages, D::Error> {
+    packages::provided_by(deserializer, ProviderKind::Cargo)
+}
+
+pub fn npm<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Packages, D::Error> {
+    packages::provided_by(deserializer, ProviderKind::Npm)
+}
+
+pub fn pipx<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Packages, D::Error> {
+    packages::provided_by(deserializer, ProviderKind::Pipx)
+}
+
+pub fn go<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Packages, D::Error> {
+    packages::provided_by(deserializer, ProviderKind::Go)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::fake::FakeRunner;
+    use crate::{Command, Direction};
+    use std::rc::Rc;
+
+    #[derive(Deserialize)]
+    struct Steps {
+        #[serde(default, deserialize_with = "cargo")]
+        cargo: Packages,
+        #[serde(default, deserialize_with = "npm")]
+        npm: Packages,
+        #[serde(default, deserialize_with = "pipx")]
+        pipx: Packages,
+    }
+
+    fn faked() -> (Rc<FakeRunner>, Context) {
+        let runner = Rc::new(FakeRunner::default());
+        let context = Context { runner: runner.clone(), ..Context::default() };
+        (runner, context)
+    }
+
+    fn messages(steps: &Packages, context: &Context) -> Vec<String> {
+        steps.explain(context).unwrap().into_iter().map(|explanation| explanation.message).collect()
+    }
+
+    #[test]
+    fn only_missing_crates_are_installed() {
+        let (runner, context) = faked();
+        runner.print("cargo install --list", "ripgrep v13.0.0:\n    rg\nfd-find v8.7.0:\n    fd\n");
+        let steps: Steps = serde_json::from_str(r#"{"cargo": ["ripgrep", "bat", "tokei"]}"#).unwrap();
+
+        steps.cargo.execute(&context).unwrap();
+
+        assert_eq!(runner.commands(), vec!["cargo install --list", "cargo install bat tokei"]);
+        assert!(context.state.borrow().installed_by_dotter("cargo", "tokei"));
+        assert!(!context.state.borrow().installed_by_dotter("cargo", "ripgrep"));
+    }
+
+    #[test]
+    fn global_npm_packages_are_read_from_json() {
+        let (runner, context) = faked();
+        runner.print("npm ls -g --depth=0 --json", r#"{"dependencies": {"prettier": {"version": "3.0.0"}, "@vue/cli": {"version": "5.0.8"}}}"#);
+        let steps: Steps = serde_json::from_str(r#"{"npm": ["prettier", "@vue/cli", "typescript"]}"#).unwrap();
+
+        assert_eq!(messages(&steps.npm, &context), vec![
+            "prettier is already installed, nothing to do",
+            "@vue/cli is already installed, nothing to do",
+            "Will install typescript with npm",
+        ]);
+    }
+
+    #[test]
+    fn pipx_packages_installed_by_dotter_are_uninstalled() {
+        let (runner, context) = faked();
+        runner.print("pipx list --json", r#"{"venvs": {"black": {}, "poetry": {}}}"#);
+        context.state.borrow_mut().installed_package("pipx", "black");
+        context.state.borrow_mut().found_package("pipx", "poetry");
+        let steps: Steps = serde_json::from_str(r#"{"pipx": ["black", "poetry"]}"#).unwrap();
+        let context = Context { direction: Direction::Rollback, ..context };
+
+        assert_eq!(messages(&steps.pipx, &context), vec![
+            "poetry was not installed by dotter, leaving it alone.",
+            "Will uninstall black with pipx.",
+        ]);
+        steps.pipx.rollback(&context).unwrap();
+
+        assert_eq!(runner.commands(), vec!["pipx list --json", "pipx uninstall black"]);
+    }
+
+    #[test]
+    fn go_binaries_are_found_in_gobin() {
+        let bin = tempfile::tempdir().unwrap();
+        fs::File::create(bin.path().join("gopls")).unwrap();
+        let (runner, context) = faked();
+        runner.print("go env GOBIN GOPATH", &format!("{}\n/home/me/go\n", bin.path().display()));
+        let steps: Packages = serde_json::from_str(r#"[{"name": "golang.org/x/tools/gopls@latest", "provider": "go"}, {"name": "github.com/go-delve/delve/cmd/dlv", "provider": "go"}]"#).unwrap();
+
+        steps.execute(&context).unwrap();
+        assert_eq!(runner.commands(), vec!["go env GOBIN GOPATH", "go install github.com/go-delve/delve/cmd/dlv@latest"]);
+
+        context.state.borrow_mut().installed_package("go", "golang.org/x/tools/gopls@latest");
+        let context = Context { direction: Direction::Rollback, ..context };
+        steps.rollback(&context).unwrap();
+        assert!(!bin.path().join("gopls").exists());
+    }
+
+    #[test]
+    fn go_binaries_are_named_after_the_last_path_element() {
+        assert_eq!(binary("golang.org/x/tools/gopls@v0.14.0"), "gopls");
+        assert_eq!(binary("github.com/segmentio/golines"), "golines");
+        assert_eq!(binary("github.com/client9/misspell/cmd/misspell@latest"), "misspell");
+        assert_eq!(binary("github.com/goreleaser/goreleaser/v2@latest"), "goreleaser");
+    }
+}
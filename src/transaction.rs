@@ -0,0 +1,249 @@
+use crate::packages;
+use crate::state::Resource;
+use crate::{Command, Context, Result};
+use std::fmt;
+
+/// Executes `steps` in order. When one of them fails, the steps that already succeeded are
+/// rolled back in reverse order before the error is returned.
+pub fn execute(steps: Vec<&dyn Command>, context: &Context) -> Result<()> {
+    let mut completed: Vec<Completed> = Vec::new();
+
+    for step in steps {
+        let before = installed_by_dotter(step, context);
+        if let Err(cause) = step.execute(context) {
+            let rollback_errors: Vec<failure::Error> = completed
+                .iter()
+                .rev()
+                .filter_map(|done| done.undo(context).err())
+                .collect();
+
+            return Err(RolledBack {
+                cause,
+                rolled_back: completed.len() - rollback_errors.len(),
+                rollback_errors,
+            }.into());
+        }
+        completed.push(Completed { step, before });
+    }
+
+    Ok(())
+}
+
+/// The packages of `step` that dotter had installed already.
+fn installed_by_dotter(step: &dyn Command, context: &Context) -> Vec<(String, String)> {
+    packages(step, context)
+        .into_iter()
+        .filter(|(manager, name)| context.state.borrow().installed_by_dotter(manager, name))
+        .collect()
+}
+
+fn packages(step: &dyn Command, context: &Context) -> Vec<(String, String)> {
+    step.resources(context)
+        .into_iter()
+        .filter_map(|resource| match resource {
+            Resource::Package(manager, name) => Some((manager, name)),
+            Resource::Link(_) => None,
+        })
+        .collect()
+}
+
+/// A step that succeeded, with the packages of it that dotter had installed before.
+struct Completed<'a> {
+    step: &'a dyn Command,
+    before: Vec<(String, String)>,
+}
+
+impl<'a> Completed<'a> {
+    /// Rolls the step back unless it was skipped. Of its packages, only those it installed in this
+    /// run are removed, not the ones an earlier run installed.
+    fn undo(&self, context: &Context) -> Result<()> {
+        if self.step.skipped() {
+            return Ok(());
+        }
+
+        let packages = packages(self.step, context);
+        if packages.is_empty() {
+            return self.step.rollback(context);
+        }
+
+        for (manager, name) in packages.iter().rev() {
+            if self.before.contains(&(manager.clone(), name.clone())) || !context.state.borrow().installed_by_dotter(manager, name) {
+                continue;
+            }
+            packages::uninstall(manager, name, context)?;
+            context.state.borrow_mut().removed_package(manager, name);
+        }
+        Ok(())
+    }
+}
+
+/// Runs `execute` for a step that installs several `packages` (manager and name) at once. If it
+/// fails under `--atomic`, the packages it installed before failing are removed with `undo`, as
+/// `execute` above only rolls back steps that completed.
+pub fn contain<E, U>(packages: &[(&str, String)], context: &Context, execute: E, undo: U) -> Result<()>
+where
+    E: FnOnce() -> Result<()>,
+    U: Fn(&str, &str) -> Result<()>,
+{
+    let before: Vec<bool> = packages
+        .iter()
+        .map(|(manager, name)| context.state.borrow().installed_by_dotter(manager, name))
+        .collect();
+
+    let cause = match execute() {
+        Ok(()) => return Ok(()),
+        Err(cause) if !context.atomic => return Err(cause),
+        Err(cause) => cause,
+    };
+
+    let mut undo_errors = Vec::new();
+    for ((manager, name), installed_before) in packages.iter().zip(before).rev() {
+        if installed_before || !context.state.borrow().installed_by_dotter(manager, name) {
+            continue;
+        }
+        match undo(manager, name) {
+            Ok(()) => context.state.borrow_mut().removed_package(manager, name),
+            Err(error) => undo_errors.push(error),
+        }
+    }
+
+    if undo_errors.is_empty() {
+        return Err(cause);
+    }
+    let undo_errors: Vec<String> = undo_errors.iter().map(|error| format!("\n  {}", error)).collect();
+    Err(format_err!("{}\nCould not remove what was installed before that:{}", cause, undo_errors.concat()))
+}
+
+#[derive(Fail, Debug)]
+pub struct RolledBack {
+    cause: failure::Error,
+    /// How many of the completed steps were rolled back without an error.
+    rolled_back: usize,
+    rollback_errors: Vec<failure::Error>,
+}
+
+impl fmt::Display for RolledBack {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}\nRolled back {} completed step(s)", self.cause, self.rolled_back)?;
+        if !self.rollback_errors.is_empty() {
+            write!(f, ", {} could not be rolled back:", self.rollback_errors.len())?;
+            for error in &self.rollback_errors {
+                write!(f, "\n  {}", error)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packages::{Packages, ProviderKind};
+    use crate::process::fake::FakeRunner;
+    use crate::settings::Settings;
+    use crate::Explanation;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    struct Step<'a> {
+        name: &'static str,
+        fails: bool,
+        fails_rollback: bool,
+        log: &'a RefCell<Vec<String>>,
+    }
+
+    impl<'a> Command for Step<'a> {
+        fn execute(&self, _context: &Context) -> Result<()> {
+            if self.fails {
+                return Err(format_err!("{} broke", self.name));
+            }
+            self.log.borrow_mut().push(format!("execute {}", self.name));
+            Ok(())
+        }
+
+        fn rollback(&self, _context: &Context) -> Result<()> {
+            if self.fails_rollback {
+                return Err(format_err!("{} could not be undone", self.name));
+            }
+            self.log.borrow_mut().push(format!("rollback {}", self.name));
+            Ok(())
+        }
+
+        fn explain(&self, _context: &Context) -> Result<Vec<Explanation>> {
+            Ok(Vec::new())
+        }
+
+        fn steps(&self) -> Vec<&dyn Command> {
+            vec![self]
+        }
+    }
+
+    fn step<'a>(name: &'static str, log: &'a RefCell<Vec<String>>) -> Step<'a> {
+        Step { name, fails: false, fails_rollback: false, log }
+    }
+
+    #[test]
+    fn completed_steps_are_rolled_back_in_reverse_when_one_fails() {
+        let log = RefCell::new(Vec::new());
+        let first = step("first", &log);
+        let second = step("second", &log);
+        let third = Step { fails: true, ..step("third", &log) };
+        let never = step("never", &log);
+
+        let error = execute(vec![&first, &second, &third, &never], &Context::default()).unwrap_err();
+
+        assert_eq!(*log.borrow(), ["execute first", "execute second", "rollback second", "rollback first"]);
+        assert_eq!(error.to_string(), "third broke\nRolled back 2 completed step(s)");
+    }
+
+    #[test]
+    fn rollback_errors_are_reported_alongside_the_original_error() {
+        let log = RefCell::new(Vec::new());
+        let first = step("first", &log);
+        let second = Step { fails_rollback: true, ..step("second", &log) };
+        let third = Step { fails: true, ..step("third", &log) };
+
+        let error = execute(vec![&first, &second, &third], &Context::default()).unwrap_err();
+
+        assert_eq!(*log.borrow(), ["execute first", "execute second", "rollback first"]);
+        assert_eq!(
+            error.to_string(),
+            "third broke\nRolled back 1 completed step(s), 1 could not be rolled back:\n  second could not be undone"
+        );
+    }
+
+    #[test]
+    fn only_packages_installed_in_this_run_are_removed() {
+        let runner = Rc::new(FakeRunner::default());
+        runner.print("pacman -Qq", "git\n");
+        let context = Context {
+            runner: runner.clone(),
+            settings: Settings { package_provider: Some(ProviderKind::Pacman), ..Settings::default() },
+            atomic: true,
+            ..Context::default()
+        };
+        context.state.borrow_mut().installed_package("pacman", "git");
+
+        let log = RefCell::new(Vec::new());
+        let packages: Packages = serde_json::from_str(r#"["git", "ripgrep"]"#).unwrap();
+        let broken = Step { fails: true, ..step("broken", &log) };
+
+        execute(vec![&packages, &broken], &context).unwrap_err();
+
+        let removed: Vec<String> = runner.commands().into_iter().filter(|command| command.contains("pacman -R")).collect();
+        assert_eq!(removed.len(), 1);
+        assert!(removed[0].ends_with("pacman -R --noconfirm ripgrep"), "{:?}", removed);
+        assert!(context.state.borrow().installed_by_dotter("pacman", "git"));
+    }
+
+    #[test]
+    fn nothing_is_rolled_back_when_everything_succeeds() {
+        let log = RefCell::new(Vec::new());
+        let first = step("first", &log);
+        let second = step("second", &log);
+
+        execute(vec![&first, &second], &Context::default()).unwrap();
+
+        assert_eq!(*log.borrow(), ["execute first", "execute second"]);
+    }
+}